@@ -6,9 +6,8 @@ use std::{
     collections::{BTreeMap, BTreeSet},
     fs,
     sync::{
-        Arc, LazyLock, Mutex, RwLock,
-        atomic::{AtomicUsize, Ordering},
-        mpsc,
+        Arc, LazyLock, Mutex, RwLock, Weak,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
     },
     thread,
 };
@@ -16,20 +15,64 @@ use std::{
 pub trait NumaReplicable: Send + Sync + 'static {
     fn allocate() -> Arc<Self>;
 
+    /// Allocates the replica for `node`, from a thread bound to that node.
+    ///
+    /// Types whose replicas differ per node override this; all others get `allocate`.
+    fn allocate_on_node(_node: usize) -> Arc<Self> {
+        Self::allocate()
+    }
+
     fn allocate_shared() -> Option<Arc<Self>> {
         None
     }
 }
 
+// Types that need no setup beyond their default value. Anything else implements the trait
+// itself, since only the type knows how a fresh replica must be initialized.
+macro_rules! impl_numa_replicable_default {
+    ($($t:ty),*) => {
+        $(
+            impl NumaReplicable for $t {
+                fn allocate() -> Arc<Self> {
+                    Arc::new(Self::default())
+                }
+            }
+        )*
+    };
+}
+
+impl_numa_replicable_default!(
+    std::sync::atomic::AtomicBool,
+    std::sync::atomic::AtomicI8,
+    std::sync::atomic::AtomicI16,
+    std::sync::atomic::AtomicI32,
+    std::sync::atomic::AtomicI64,
+    std::sync::atomic::AtomicIsize,
+    std::sync::atomic::AtomicU8,
+    std::sync::atomic::AtomicU16,
+    std::sync::atomic::AtomicU32,
+    AtomicU64,
+    AtomicUsize
+);
+
+impl<T: NumaReplicable + Default, const N: usize> NumaReplicable for [T; N] {
+    fn allocate() -> Arc<Self> {
+        Arc::new(std::array::from_fn(|_| T::default()))
+    }
+}
+
 type CpuIndex = usize;
 type NumaIndex = usize;
 
+const CACHE_LINE_SIZE: usize = 64;
+/// The largest number of CPUs mainline Linux supports (`CONFIG_NR_CPUS`), bounding CPU lists.
+const MAX_CPUS: CpuIndex = 8192;
+const LOCAL_DISTANCE: u32 = 10;
+const REMOTE_DISTANCE: u32 = 20;
+
 static SYSTEM_THREADS: LazyLock<CpuIndex> =
     LazyLock::new(|| thread::available_parallelism().map(|x| x.get()).unwrap_or(1).max(1));
 
-#[cfg(all(target_os = "linux", not(target_os = "android")))]
-static PROCESSOR_AFFINITY: LazyLock<BTreeSet<CpuIndex>> = LazyLock::new(get_process_affinity);
-
 #[cfg(all(target_os = "linux", not(target_os = "android")))]
 fn get_process_affinity() -> BTreeSet<CpuIndex> {
     use libc::{CPU_ISSET, CPU_SETSIZE, CPU_ZERO, cpu_set_t, sched_getaffinity};
@@ -37,14 +80,242 @@ fn get_process_affinity() -> BTreeSet<CpuIndex> {
     let mut mask: cpu_set_t = unsafe { std::mem::zeroed() };
     unsafe { CPU_ZERO(&mut mask) };
 
+    // Some sandboxes deny the call; report no CPUs then rather than guessing which are usable
     let status = unsafe { sched_getaffinity(0, std::mem::size_of::<cpu_set_t>(), &mut mask as *mut cpu_set_t) };
     if status != 0 {
-        panic!("sched_getaffinity failed");
+        return BTreeSet::new();
     }
 
     (0..(CPU_SETSIZE as usize)).filter(|&cpu| unsafe { CPU_ISSET(cpu, &mask) }).collect::<BTreeSet<CpuIndex>>()
 }
 
+thread_local! {
+    /// The node the current thread was last bound to through `NumaConfig`.
+    static BOUND_NODE: std::cell::Cell<Option<NumaIndex>> = const { std::cell::Cell::new(None) };
+
+    /// When the current thread was last bound through `bind_current_thread_to_numa_node_throttled`.
+    static LAST_THROTTLED_BIND: std::cell::Cell<Option<std::time::Instant>> = const { std::cell::Cell::new(None) };
+}
+
+/// Returns the OS node id of the memory backing `addr`, faulting the page in if necessary.
+fn node_of_address(addr: *const u8) -> Option<usize> {
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    {
+        const MPOL_F_NODE: libc::c_ulong = 1 << 0;
+        const MPOL_F_ADDR: libc::c_ulong = 1 << 1;
+
+        let mut node: libc::c_int = -1;
+        let status = unsafe {
+            libc::syscall(
+                libc::SYS_get_mempolicy,
+                &mut node as *mut libc::c_int,
+                std::ptr::null_mut::<libc::c_ulong>(),
+                0 as libc::c_ulong,
+                addr,
+                MPOL_F_NODE | MPOL_F_ADDR,
+            )
+        };
+
+        if status == 0 && node >= 0 {
+            return Some(node as usize);
+        }
+    }
+
+    None
+}
+
+fn current_cpu() -> Option<CpuIndex> {
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    {
+        let cpu = unsafe { libc::sched_getcpu() };
+        if cpu >= 0 {
+            return Some(cpu as CpuIndex);
+        }
+    }
+
+    None
+}
+
+fn bind_current_thread_to_cpus(cpus: &BTreeSet<CpuIndex>) {
+    if let Err(e) = try_bind_current_thread_to_cpus(cpus) {
+        panic!("{e}");
+    }
+}
+
+fn try_bind_current_thread_to_cpus(cpus: &BTreeSet<CpuIndex>) -> Result<(), NumaError> {
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    {
+        use libc::{CPU_SET, CPU_SETSIZE, CPU_ZERO, EINVAL, cpu_set_t, sched_setaffinity, sched_yield};
+
+        // CPU_SET does not bounds-check, and the kernel rejects CPUs it cannot run on anyway
+        if cpus.last().is_some_and(|&cpu| cpu >= CPU_SETSIZE as usize) {
+            return Err(NumaError::BindFailed { errno: EINVAL });
+        }
+
+        let mut mask: cpu_set_t = unsafe { std::mem::zeroed() };
+        unsafe { CPU_ZERO(&mut mask) };
+
+        for cpu in cpus {
+            unsafe { CPU_SET(*cpu, &mut mask) };
+        }
+
+        let status = unsafe { sched_setaffinity(0, std::mem::size_of::<cpu_set_t>(), &mask as *const cpu_set_t) };
+        if status != 0 {
+            let errno = std::io::Error::last_os_error().raw_os_error().unwrap_or(0);
+            return Err(NumaError::BindFailed { errno });
+        }
+
+        unsafe { sched_yield() };
+    }
+
+    Ok(())
+}
+
+/// Reads one byte from every cache line spanned by `value`, returning the number of lines read.
+fn touch_cache_lines<T: ?Sized>(value: &T) -> usize {
+    let start = value as *const T as *const u8 as usize;
+    let end = start + std::mem::size_of_val(value);
+    if start == end {
+        return 0;
+    }
+
+    let mut lines = 0;
+    for line in (start & !(CACHE_LINE_SIZE - 1)..end).step_by(CACHE_LINE_SIZE) {
+        let ptr = line.max(start) as *const std::mem::MaybeUninit<u8>;
+        std::hint::black_box(unsafe { ptr.read_volatile() });
+        lines += 1;
+    }
+    lines
+}
+
+/// Runs `f` with the current thread restricted to `cpus`, restoring the previous affinity
+/// afterwards, even if `f` panics.
+pub fn with_affinity<R, F: FnOnce() -> R>(cpus: &BTreeSet<CpuIndex>, f: F) -> R {
+    let _guard = AffinityGuard::capture();
+    bind_current_thread_to_cpus(cpus);
+    BOUND_NODE.set(None);
+    f()
+}
+
+/// A thread started with `NumaConfig::spawn_bound` that was successfully bound to its node.
+pub struct BoundThread<R> {
+    /// `None` only for threads that could not be bound, which are never handed out.
+    handle: thread::JoinHandle<Option<R>>,
+}
+
+impl<R> std::fmt::Debug for BoundThread<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("BoundThread").field("thread", self.handle.thread()).finish()
+    }
+}
+
+impl<R> BoundThread<R> {
+    /// Waits for the thread to finish and returns the result of its closure, or the payload
+    /// of its panic.
+    pub fn join(self) -> thread::Result<R> {
+        self.handle.join().map(|result| result.expect("spawn_bound returned an unbound thread"))
+    }
+
+    pub fn thread(&self) -> &thread::Thread {
+        self.handle.thread()
+    }
+}
+
+/// Restores the affinity of the current thread, as it was when the guard was created, on drop.
+///
+/// The guard belongs to the thread that created it and cannot be sent to another thread.
+pub struct AffinityGuard {
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    cpus: BTreeSet<CpuIndex>,
+    node: Option<NumaIndex>,
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+
+impl AffinityGuard {
+    fn capture() -> Self {
+        Self {
+            #[cfg(all(target_os = "linux", not(target_os = "android")))]
+            cpus: get_process_affinity(),
+            node: BOUND_NODE.get(),
+            _not_send: std::marker::PhantomData,
+        }
+    }
+}
+
+impl Drop for AffinityGuard {
+    fn drop(&mut self) {
+        // An affinity that could not be read cannot be restored either
+        #[cfg(all(target_os = "linux", not(target_os = "android")))]
+        if !self.cpus.is_empty() {
+            bind_current_thread_to_cpus(&self.cpus);
+        }
+        BOUND_NODE.set(self.node);
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NumaError {
+    /// The requested node does not exist or has no CPUs.
+    OutOfRange,
+    /// A CPU list is not a comma-separated list of CPU indices and ranges.
+    InvalidCpuList,
+    /// An affinity specification is not of the form `node:<list>` or `cpu:<list>`.
+    InvalidAffinitySpec,
+    /// A node does not have enough free memory for an allocation, with sizes in bytes.
+    InsufficientMemory { node: NumaIndex, needed: usize, available: usize },
+    /// The topology has no nodes with CPUs to replicate to.
+    NoNodes,
+    /// The OS rejected the affinity change, for example because a cgroup excludes the CPUs
+    /// or a CPU index does not fit into the affinity mask.
+    BindFailed { errno: i32 },
+}
+
+impl std::fmt::Display for NumaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::OutOfRange => write!(f, "NUMA node does not exist or has no CPUs"),
+            Self::InvalidCpuList => write!(f, "invalid CPU list"),
+            Self::InvalidAffinitySpec => write!(f, "invalid affinity specification"),
+            Self::InsufficientMemory { node, needed, available } => {
+                write!(f, "NUMA node {node} has {available} bytes free but {needed} bytes are needed")
+            }
+            Self::NoNodes => write!(f, "no NUMA nodes with CPUs found"),
+            Self::BindFailed { errno } => {
+                write!(f, "sched_setaffinity failed: {}", std::io::Error::from_raw_os_error(*errno))
+            }
+        }
+    }
+}
+
+impl std::error::Error for NumaError {}
+
+/// A thread placement read from configuration, such as `node:1`, `node:0-1` or `cpu:4`.
+///
+/// Node ids refer to OS node ids and both forms accept the Linux CPU list syntax.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AffinitySpec {
+    Nodes(Vec<usize>),
+    Cpus(Vec<CpuIndex>),
+}
+
+impl std::str::FromStr for AffinitySpec {
+    type Err = NumaError;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (kind, list) = spec.trim().split_once(':').ok_or(NumaError::InvalidAffinitySpec)?;
+        let list = parse_cpu_list(list).map_err(|_| NumaError::InvalidAffinitySpec)?;
+        if list.is_empty() {
+            return Err(NumaError::InvalidAffinitySpec);
+        }
+
+        match kind {
+            "node" => Ok(Self::Nodes(list)),
+            "cpu" => Ok(Self::Cpus(list)),
+            _ => Err(NumaError::InvalidAffinitySpec),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Default)]
 pub struct NumaReplicatedAccessToken {
     index: NumaIndex,
@@ -56,11 +327,22 @@ impl NumaReplicatedAccessToken {
     }
 }
 
-#[derive(Clone)]
+/// The NUMA topology visible to the process.
+///
+/// Nodes are stored in ascending order of their OS node id and the CPUs of each node in
+/// ascending order of their CPU id, independent of the order in which they were discovered.
+/// Two configurations built from the same machine therefore always agree on which node
+/// index refers to which set of CPUs, across runs and across processes.
+#[derive(Clone, PartialEq, Eq)]
 pub struct NumaConfig {
     nodes: Vec<BTreeSet<CpuIndex>>,
-    node_by_cpu: BTreeMap<CpuIndex, NumaIndex>,
+    /// The node of every CPU, indexed by CPU, for constant-time lookups on hot paths.
+    node_by_cpu: Vec<Option<NumaIndex>>,
     highest_cpu_index: CpuIndex,
+    distances: Vec<Vec<u32>>,
+    os_node_ids: Vec<usize>,
+    /// Nodes known to have CPUs but no local memory.
+    memoryless: BTreeSet<NumaIndex>,
 }
 
 impl Default for NumaConfig {
@@ -77,9 +359,28 @@ impl NumaConfig {
     pub const fn empty() -> Self {
         Self {
             nodes: Vec::new(),
-            node_by_cpu: BTreeMap::new(),
+            node_by_cpu: Vec::new(),
             highest_cpu_index: 0,
+            distances: Vec::new(),
+            os_node_ids: Vec::new(),
+            memoryless: BTreeSet::new(),
+        }
+    }
+
+    /// Creates a configuration with the given CPUs on each node, for example to test placement
+    /// logic against a topology other than the one of the current machine.
+    ///
+    /// Nodes keep the given order, and nodes without CPUs are kept as well. All distances are
+    /// the conventional local and remote distances.
+    pub fn from_nodes(nodes: &[&[CpuIndex]]) -> Self {
+        let mut cfg = Self::empty();
+        cfg.nodes.resize_with(nodes.len(), BTreeSet::new);
+        for (node, cpus) in nodes.iter().enumerate() {
+            for &cpu in cpus.iter() {
+                cfg.add_cpu_to_node(node, cpu);
+            }
         }
+        cfg
     }
 
     pub fn from_system() -> Self {
@@ -89,16 +390,161 @@ impl NumaConfig {
 
         #[cfg(all(target_os = "linux", not(target_os = "android")))]
         {
-            let mut cfg = NumaConfig::from_system_numa();
+            // Read on every call, so that a refresh picks up a resized cpuset
+            let affinity = get_process_affinity();
+            let mut cfg = NumaConfig::from_system_numa(&affinity);
             cfg.remove_empty_numa_nodes();
+
+            // A container may hide the topology or leave no usable CPU on any node
+            if cfg.nodes.is_empty() {
+                return Self::from_affinity(&affinity);
+            }
             cfg
         }
     }
 
+    /// Returns whether the system exposes its NUMA topology.
+    ///
+    /// Everything in this module also works when it does not, with all CPUs on a single node
+    /// and a single shared replica, so this is purely informational.
+    pub fn is_available() -> bool {
+        #[cfg(all(target_os = "linux", not(target_os = "android")))]
+        if let Ok(ids) = fs::read_to_string("/sys/devices/system/node/online") {
+            return parse_cpu_list(&ids).is_ok_and(|ids| !ids.is_empty());
+        }
+
+        false
+    }
+
+    /// Returns whether any CPU of this configuration has since moved to another node or
+    /// disappeared, as can happen to the vCPUs of a live-migrated virtual machine.
+    ///
+    /// A drifted configuration should be replaced, for example with
+    /// `NumaReplicationContext::refresh_topology`, so that replicas follow the new layout.
+    ///
+    /// Only the online node list and the `cpulist` of each node are read, not distances or
+    /// memory sizes, so the check is cheap enough to run periodically. Without a readable
+    /// node list no drift can be detected and `false` is returned.
+    pub fn detect_topology_drift(&self) -> bool {
+        #[cfg(not(all(target_os = "linux", not(target_os = "android"))))]
+        return false;
+
+        #[cfg(all(target_os = "linux", not(target_os = "android")))]
+        {
+            let node_ids = fs::read_to_string("/sys/devices/system/node/online").map(|ids| parse_cpu_list(&ids));
+            let Ok(Ok(node_ids)) = node_ids else {
+                return false;
+            };
+
+            let mut os_node_by_cpu = BTreeMap::new();
+            for node in node_ids {
+                let path = format!("/sys/devices/system/node/node{node}/cpulist");
+                if let Ok(Ok(cpus)) = fs::read_to_string(&path).map(|ids| parse_cpu_list(&ids)) {
+                    os_node_by_cpu.extend(cpus.into_iter().map(|cpu| (cpu, node)));
+                }
+            }
+
+            self.drifted_with(|cpu| os_node_by_cpu.get(&cpu).copied())
+        }
+    }
+
+    /// Splits a single-node configuration into one pseudo node per CPU package.
+    ///
+    /// Some multi-socket systems present all memory as a single NUMA node, in which case
+    /// nothing is replicated even though every socket has its own caches. Once the split
+    /// configuration is installed with `NumaReplicationContext::set_numa_config`, threads are
+    /// bound per socket and replicas are placed by first touch from each socket. All pseudo
+    /// nodes keep OS node id 0. This is opt-in, and configurations with more than one node or
+    /// with unknown packages are returned unchanged.
+    pub fn split_by_package(&self) -> Self {
+        self.split_by(|cpu| {
+            let path = format!("/sys/devices/system/cpu/cpu{cpu}/topology/physical_package_id");
+            fs::read_to_string(path).ok()?.trim().parse().ok()
+        })
+    }
+
+    fn split_by(&self, package_of: impl Fn(CpuIndex) -> Option<usize>) -> Self {
+        if self.nodes.len() != 1 {
+            return self.clone();
+        }
+
+        let mut packages = BTreeMap::<usize, Vec<CpuIndex>>::new();
+        for &cpu in &self.nodes[0] {
+            let Some(package) = package_of(cpu) else {
+                return self.clone();
+            };
+            packages.entry(package).or_default().push(cpu);
+        }
+
+        if packages.len() == 1 {
+            return self.clone();
+        }
+
+        let mut cfg = NumaConfig::empty();
+        for (node, cpus) in packages.values().enumerate() {
+            for &cpu in cpus {
+                cfg.add_cpu_to_node(node, cpu);
+            }
+        }
+        cfg.os_node_ids = vec![self.os_node_id(0); cfg.nodes.len()];
+        cfg
+    }
+
+    fn drifted_from(&self, current: &NumaConfig) -> bool {
+        self.drifted_with(|cpu| current.node_of_cpu(cpu).map(|node| current.os_node_id(node)))
+    }
+
+    /// Returns whether `os_node_of` puts any CPU on another OS node than this configuration.
+    fn drifted_with(&self, os_node_of: impl Fn(CpuIndex) -> Option<usize>) -> bool {
+        self.nodes
+            .iter()
+            .enumerate()
+            .any(|(node, cpus)| cpus.iter().any(|&cpu| os_node_of(cpu) != Some(self.os_node_id(node))))
+    }
+
     pub const fn num_numa_nodes(&self) -> NumaIndex {
         self.nodes.len()
     }
 
+    /// Returns the dense indices of all nodes in ascending order.
+    pub fn nodes(&self) -> impl Iterator<Item = NumaIndex> {
+        0..self.nodes.len()
+    }
+
+    /// Returns the CPUs of `node` in ascending order, or `None` if the node does not exist.
+    ///
+    /// Without NUMA support, a single node covers all CPUs of the system.
+    pub fn cpus_of(&self, node: NumaIndex) -> Option<impl Iterator<Item = CpuIndex> + '_> {
+        self.nodes.get(node).map(|cpus| cpus.iter().copied())
+    }
+
+    /// Returns one CPU per physical core of `node`, the lowest of its SMT siblings, so that
+    /// compute-bound threads do not share the execution units of a core.
+    ///
+    /// CPUs whose siblings cannot be determined are treated as cores of their own.
+    pub fn physical_cpus_of(&self, node: NumaIndex) -> Vec<CpuIndex> {
+        self.physical_cpus_with(node, |cpu| {
+            let path = format!("/sys/devices/system/cpu/cpu{cpu}/topology/thread_siblings_list");
+            parse_cpu_list(&fs::read_to_string(path).ok()?).ok()
+        })
+    }
+
+    fn physical_cpus_with(
+        &self, node: NumaIndex, siblings_of: impl Fn(CpuIndex) -> Option<Vec<CpuIndex>>,
+    ) -> Vec<CpuIndex> {
+        let Some(cpus) = self.nodes.get(node) else {
+            return Vec::new();
+        };
+
+        cpus.iter()
+            .copied()
+            .filter(|&cpu| {
+                let siblings = siblings_of(cpu).unwrap_or_default();
+                siblings.iter().filter(|sibling| cpus.contains(sibling)).all(|&sibling| sibling >= cpu)
+            })
+            .collect()
+    }
+
     pub const fn requires_memory_replication(&self) -> bool {
         self.nodes.len() > 1
     }
@@ -120,7 +566,8 @@ impl NumaConfig {
     }
 
     pub fn distribute_threads_among_numa_nodes(&self, num_threads: CpuIndex) -> Vec<NumaIndex> {
-        if self.nodes.len() == 1 {
+        // An empty topology never suggests binding, so every thread is placed on node 0
+        if self.nodes.len() <= 1 {
             return vec![0; num_threads];
         }
 
@@ -146,240 +593,2729 @@ impl NumaConfig {
         nodes
     }
 
-    pub fn bind_current_thread_to_numa_node(&self, node: NumaIndex) -> NumaReplicatedAccessToken {
-        assert!(node < self.nodes.len() && !self.nodes[node].is_empty());
+    /// Distributes threads across nodes in proportion to `weights`, for example the memory
+    /// bandwidth of each node, instead of the number of CPUs.
+    ///
+    /// Nodes without a weight get none. If no weight is positive, the threads are spread
+    /// evenly. The assignment is deterministic and wraps around for any number of threads.
+    pub fn distribute_threads_weighted(&self, num_threads: CpuIndex, weights: &[f64]) -> Vec<NumaIndex> {
+        if self.nodes.is_empty() {
+            return vec![0; num_threads];
+        }
 
-        #[cfg(all(target_os = "linux", not(target_os = "android")))]
-        {
-            use libc::{CPU_SET, CPU_ZERO, cpu_set_t, sched_setaffinity, sched_yield};
+        let weight = |node: usize| weights.get(node).copied().filter(|w| w.is_finite() && *w > 0.0);
+        let even = (0..self.nodes.len()).all(|node| weight(node).is_none());
 
-            let mut mask: cpu_set_t = unsafe { std::mem::zeroed() };
-            unsafe { CPU_ZERO(&mut mask) };
+        let mut nodes = Vec::with_capacity(num_threads);
+        let mut occupation = vec![0usize; self.nodes.len()];
 
-            for cpu in &self.nodes[node] {
-                unsafe { CPU_SET(*cpu, &mut mask) };
-            }
+        for _ in 0..num_threads {
+            let fill = |node: usize| {
+                let weight = if even { Some(1.0) } else { weight(node) };
+                weight.map(|w| (occupation[node] + 1) as f64 / w)
+            };
 
-            let status = unsafe { sched_setaffinity(0, std::mem::size_of::<cpu_set_t>(), &mask as *const cpu_set_t) };
-            if status != 0 {
-                panic!("sched_setaffinity failed");
-            }
+            let best_node = (0..self.nodes.len())
+                .filter_map(|node| fill(node).map(|f| (node, f)))
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map_or(0, |(node, _)| node);
 
-            unsafe { sched_yield() };
+            nodes.push(best_node);
+            occupation[best_node] += 1;
         }
 
-        NumaReplicatedAccessToken::new(node)
+        nodes
     }
 
-    pub fn execute_on_numa_node<F: FnOnce() + Send + 'static>(&self, n: NumaIndex, f: F) {
-        #[cfg(not(target_arch = "wasm32"))]
-        {
-            let cfg = self.clone();
-            let handle = thread::spawn(move || {
-                cfg.bind_current_thread_to_numa_node(n);
-                f();
-            });
-            handle.join().unwrap();
+    pub fn bind_current_thread_to_numa_node(&self, node: NumaIndex) -> NumaReplicatedAccessToken {
+        self.try_bind_current_thread_to_numa_node(node).unwrap()
+    }
+
+    /// Spawns a thread named `name` that binds itself to `node` before running `f`, so that
+    /// everything `f` allocates is first touched on that node.
+    ///
+    /// Returns once the thread is bound. Fails with `NumaError::OutOfRange` wrapped in an
+    /// `InvalidInput` error if `node` does not exist or has no CPUs, with any other error of
+    /// `try_bind_current_thread_to_numa_node` wrapped in an `Other` error, or with the error of
+    /// `thread::Builder::spawn`. If binding fails, the thread ends without running `f`.
+    pub fn spawn_bound<F, R>(self: &Arc<Self>, node: NumaIndex, name: &str, f: F) -> std::io::Result<BoundThread<R>>
+    where
+        F: FnOnce(NumaReplicatedAccessToken) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let to_io_error = |e: NumaError| {
+            let kind =
+                if e == NumaError::OutOfRange { std::io::ErrorKind::InvalidInput } else { std::io::ErrorKind::Other };
+            std::io::Error::new(kind, e)
+        };
+
+        if node >= self.num_numa_nodes() || self.nodes[node].is_empty() {
+            return Err(to_io_error(NumaError::OutOfRange));
         }
 
-        #[cfg(target_arch = "wasm32")]
-        {
-            let _ = n;
-            f();
+        let cfg = self.clone();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let handle = std::thread::Builder::new().name(name.to_owned()).spawn(move || {
+            let token = match cfg.try_bind_current_thread_to_numa_node(node) {
+                Ok(token) => token,
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                    return None;
+                }
+            };
+            let _ = tx.send(Ok(()));
+            Some(f(token))
+        })?;
+
+        if let Ok(Err(e)) = rx.recv() {
+            let _ = handle.join();
+            return Err(to_io_error(e));
         }
+        Ok(BoundThread { handle })
     }
 
-    fn add_cpu_to_node(&mut self, node: NumaIndex, cpu: CpuIndex) {
-        if self.nodes.len() <= node {
-            self.nodes.resize_with(node + 1, BTreeSet::new);
+    /// Binds the current thread to `node` until the returned guard is dropped, which restores
+    /// the previous affinity. Intended for code running on threads it does not own.
+    pub fn bind_current_thread_to_numa_node_scoped(&self, node: NumaIndex) -> AffinityGuard {
+        let guard = AffinityGuard::capture();
+        self.bind_current_thread_to_numa_node(node);
+        guard
+    }
+
+    /// Binds the current thread to `node` unless it was already bound through this function
+    /// within the last `min_interval`, in which case `None` is returned and the thread keeps
+    /// its current affinity.
+    ///
+    /// The throttling is advisory: it only protects against rebalancers that re-pin threads
+    /// faster than their caches can warm up, and does not account for binds made otherwise.
+    pub fn bind_current_thread_to_numa_node_throttled(
+        &self, node: NumaIndex, min_interval: std::time::Duration,
+    ) -> Option<NumaReplicatedAccessToken> {
+        let now = std::time::Instant::now();
+        if LAST_THROTTLED_BIND.get().is_some_and(|last| now.duration_since(last) < min_interval) {
+            return None;
         }
 
-        self.nodes[node].insert(cpu);
-        self.node_by_cpu.insert(cpu, node);
-        self.highest_cpu_index = self.highest_cpu_index.max(cpu);
+        LAST_THROTTLED_BIND.set(Some(now));
+        Some(self.bind_current_thread_to_numa_node(node))
     }
 
-    fn remove_empty_numa_nodes(&mut self) {
-        self.nodes.retain(|cpus| !cpus.is_empty());
-
-        self.node_by_cpu.clear();
-        for (node, cpus) in self.nodes.iter().enumerate() {
-            for &cpu in cpus {
-                self.node_by_cpu.insert(cpu, node);
-            }
+    /// Binds the current thread to `node`, failing with `NumaError::OutOfRange`
+    /// if the node does not exist or has no CPUs, or with `NumaError::BindFailed`
+    /// if the OS does not allow the thread to run on its CPUs.
+    ///
+    /// On failure the thread keeps its current affinity, so binding to a node without CPUs
+    /// is a no-op rather than a fallback onto another node.
+    ///
+    /// The affinity is inherited by threads spawned afterwards, so a per-node supervisor only
+    /// needs to bind itself. Children should call `adopt_inherited_node` to obtain their access
+    /// token. No memory policy is set; allocations follow the default local policy and thus
+    /// land on the node the affinity restricts the thread to.
+    pub fn try_bind_current_thread_to_numa_node(
+        &self, node: NumaIndex,
+    ) -> Result<NumaReplicatedAccessToken, NumaError> {
+        if node >= self.nodes.len() || self.nodes[node].is_empty() {
+            return Err(NumaError::OutOfRange);
         }
 
-        self.highest_cpu_index = self.node_by_cpu.keys().copied().max().unwrap_or(0);
+        try_bind_current_thread_to_cpus(&self.nodes[node])?;
+        BOUND_NODE.set(Some(node));
+        Ok(NumaReplicatedAccessToken::new(node))
     }
 
-    fn from_system_numa() -> Self {
-        let mut cfg = NumaConfig::empty();
-
+    /// Returns the token of the node the current thread is confined to by an affinity it
+    /// inherited from its parent, or `None` if its CPUs span several nodes.
+    pub fn adopt_inherited_node(&self) -> Option<NumaReplicatedAccessToken> {
         #[cfg(all(target_os = "linux", not(target_os = "android")))]
         {
-            let fallback = || {
-                let mut cfg = NumaConfig::empty();
-                for cpu in 0..*SYSTEM_THREADS {
-                    if PROCESSOR_AFFINITY.contains(&cpu) {
-                        cfg.add_cpu_to_node(0, cpu);
-                    }
-                }
-                cfg
-            };
-
-            let Ok(node_ids) = fs::read_to_string("/sys/devices/system/node/online").map(remove_whitespace) else {
-                return fallback();
-            };
-
-            if node_ids.is_empty() {
-                return fallback();
+            let nodes = get_process_affinity().iter().filter_map(|&cpu| self.node_of_cpu(cpu)).collect::<BTreeSet<_>>();
+            if let [node] = nodes.into_iter().collect::<Vec<_>>()[..] {
+                BOUND_NODE.set(Some(node));
+                return Some(NumaReplicatedAccessToken::new(node));
             }
+        }
 
-            for node in parse_cpu_indices(&node_ids) {
-                let path = format!("/sys/devices/system/node/node{node}/cpulist");
-                let cpu_ids = fs::read_to_string(&path);
-                if cpu_ids.is_err() {
-                    return fallback();
-                }
+        None
+    }
 
-                let cpu_ids = remove_whitespace(cpu_ids.unwrap());
-                for cpu in parse_cpu_indices(&cpu_ids) {
-                    if PROCESSOR_AFFINITY.contains(&cpu) {
-                        cfg.add_cpu_to_node(node, cpu);
-                    }
-                }
-            }
-        }
+    /// Binds the current thread to the performance cores of `node`.
+    ///
+    /// Intended for latency-sensitive threads on hybrid CPUs. If the node has no cores
+    /// classified as `Performance`, the thread is bound to every CPU of the node instead.
+    pub fn bind_current_thread_to_numa_node_performance(&self, node: NumaIndex) -> NumaReplicatedAccessToken {
+        assert!(node < self.nodes.len() && !self.nodes[node].is_empty());
 
-        cfg
+        bind_current_thread_to_cpus(&self.performance_cpus(node, core_class));
+        BOUND_NODE.set(Some(node));
+        NumaReplicatedAccessToken::new(node)
     }
-}
 
-fn parse_cpu_indices(cpu_ids: &str) -> Vec<usize> {
-    if cpu_ids.is_empty() {
-        return Vec::new();
+    fn performance_cpus(&self, node: NumaIndex, classify: impl Fn(CpuIndex) -> CoreClass) -> BTreeSet<CpuIndex> {
+        let cpus = self.nodes[node].iter().copied().filter(|&cpu| classify(cpu) == CoreClass::Performance);
+        let cpus = cpus.collect::<BTreeSet<_>>();
+
+        if cpus.is_empty() { self.nodes[node].clone() } else { cpus }
     }
 
-    let mut indices = Vec::new();
-    for segment in cpu_ids.split(',').filter(|s| !s.is_empty()) {
-        let parts: Vec<_> = segment.split('-').collect();
-        match parts.len() {
-            1 => indices.push(parts[0].parse::<usize>().unwrap()),
-            2 => {
-                let first = parts[0].parse::<usize>().unwrap();
-                let last = parts[1].parse::<usize>().unwrap();
-                for cpu in first..=last {
-                    indices.push(cpu);
-                }
-            }
-            _ => {}
+    /// Returns whether the current thread runs on `node`.
+    ///
+    /// This is a cheap check intended for assertions: if the thread was bound through this
+    /// configuration, the cached node of that binding is used without querying the OS. The
+    /// answer is best-effort and may be stale if the affinity was changed by other means.
+    pub fn on_node(&self, node: NumaIndex) -> bool {
+        match BOUND_NODE.get() {
+            Some(bound) => bound == node,
+            None => current_cpu().and_then(|cpu| self.node_of_cpu(cpu)) == Some(node),
         }
     }
-    indices
-}
 
-fn remove_whitespace(s: String) -> String {
-    s.chars().filter(|c| !c.is_whitespace()).collect()
-}
+    /// Binds the current thread to the CPUs of `node` selected by `policy` with respect to the
+    /// CPUs isolated from the scheduler through `isolcpus`.
+    ///
+    /// Isolated CPUs are only part of the topology if the process was explicitly allowed to run
+    /// on them. If the policy excludes every CPU of the node, all of its CPUs are used instead.
+    pub fn bind_current_thread_to_numa_node_isolated(
+        &self, node: NumaIndex, policy: IsolationPolicy,
+    ) -> NumaReplicatedAccessToken {
+        assert!(node < self.nodes.len() && !self.nodes[node].is_empty());
 
-pub trait NumaReplicatedBase: Send + Sync {
-    fn on_numa_config_changed(&self);
-    fn get_numa_config(&self) -> NumaConfig;
-}
+        bind_current_thread_to_cpus(&self.isolation_cpus(node, policy, &isolated_cpus()));
+        BOUND_NODE.set(Some(node));
+        NumaReplicatedAccessToken::new(node)
+    }
 
-pub struct NumaReplicationContext {
-    config: RwLock<NumaConfig>,
-    thread_count: AtomicUsize,
-    tracked: Mutex<Vec<Arc<dyn NumaReplicatedBase>>>,
-}
+    fn isolation_cpus(
+        &self, node: NumaIndex, policy: IsolationPolicy, isolated: &BTreeSet<CpuIndex>,
+    ) -> BTreeSet<CpuIndex> {
+        let cpus = match policy {
+            IsolationPolicy::Ignore => return self.nodes[node].clone(),
+            IsolationPolicy::Avoid => self.nodes[node].difference(isolated).copied().collect::<BTreeSet<_>>(),
+            IsolationPolicy::Only => self.nodes[node].intersection(isolated).copied().collect::<BTreeSet<_>>(),
+        };
 
-impl NumaReplicationContext {
-    pub fn new(cfg: NumaConfig) -> Self {
-        Self {
-            config: RwLock::new(cfg),
-            thread_count: AtomicUsize::new(1),
-            tracked: Mutex::new(Vec::new()),
-        }
+        if cpus.is_empty() { self.nodes[node].clone() } else { cpus }
     }
 
-    pub fn attach(&self, obj: Arc<dyn NumaReplicatedBase>) {
-        self.tracked.lock().unwrap().push(obj);
+    /// Binds the current thread to the single CPU `cpu`, failing with `NumaError::OutOfRange`
+    /// if the CPU is not part of this configuration or with `NumaError::BindFailed` if the OS
+    /// rejects it.
+    pub fn bind_current_thread_to_cpu(&self, cpu: CpuIndex) -> Result<NumaReplicatedAccessToken, NumaError> {
+        let node = self.node_of_cpu(cpu).ok_or(NumaError::OutOfRange)?;
+
+        try_bind_current_thread_to_cpus(&BTreeSet::from([cpu]))?;
+        BOUND_NODE.set(Some(node));
+        Ok(NumaReplicatedAccessToken::new(node))
     }
 
-    pub fn get_numa_config(&self) -> NumaConfig {
-        self.config.read().unwrap().clone()
+    /// Binds the current thread according to an affinity specification such as `node:0-1`.
+    pub fn bind_from_spec(&self, spec: &str) -> Result<(), NumaError> {
+        let spec = spec.parse::<AffinitySpec>()?;
+        let cpus = self.affinity_cpus(&spec)?;
+
+        try_bind_current_thread_to_cpus(&cpus)?;
+        BOUND_NODE.set(match spec {
+            AffinitySpec::Nodes(nodes) if nodes.len() == 1 => self.dense_index_of_node(nodes[0]),
+            _ => None,
+        });
+        Ok(())
     }
 
-    pub fn set_thread_count(&self, threads: usize) {
-        let previous = self.thread_count.swap(threads, Ordering::Release);
-        if previous == threads {
-            return;
+    fn affinity_cpus(&self, spec: &AffinitySpec) -> Result<BTreeSet<CpuIndex>, NumaError> {
+        match spec {
+            AffinitySpec::Nodes(nodes) => {
+                let mut cpus = BTreeSet::new();
+                for &node in nodes {
+                    let node = self.dense_index_of_node(node).ok_or(NumaError::OutOfRange)?;
+                    cpus.extend(&self.nodes[node]);
+                }
+                Ok(cpus)
+            }
+            AffinitySpec::Cpus(cpus) => {
+                if cpus.iter().any(|&cpu| self.node_of_cpu(cpu).is_none()) {
+                    return Err(NumaError::OutOfRange);
+                }
+                Ok(cpus.iter().copied().collect())
+            }
         }
+    }
 
-        let tracked = self.tracked.lock().unwrap().clone();
-        for obj in tracked {
-            obj.on_numa_config_changed();
+    pub fn execute_on_numa_node<F: FnOnce() + Send>(&self, n: NumaIndex, f: F) {
+        #[cfg(not(target_arch = "wasm32"))]
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                self.bind_current_thread_to_numa_node(n);
+                f();
+            });
+        });
+
+        #[cfg(target_arch = "wasm32")]
+        {
+            let _ = n;
+            f();
         }
     }
 
-    pub fn get_thread_count(&self) -> usize {
-        self.thread_count.load(Ordering::Acquire)
+    /// Runs `f` once per entry of `nodes`, each on its own thread bound to that node, and
+    /// returns the results in the same order once all of them have finished.
+    ///
+    /// The threads run concurrently, and a panic in any of them is propagated to the caller.
+    pub fn execute_on_numa_nodes<R: Send, F: Fn(NumaIndex) -> R + Sync>(&self, nodes: &[NumaIndex], f: F) -> Vec<R> {
+        self.try_execute_on_numa_nodes(nodes, f).unwrap()
     }
-}
 
-pub struct NumaReplicated<T: NumaReplicable> {
-    ctx: Arc<NumaReplicationContext>,
-    instances: RwLock<Vec<Arc<T>>>,
-}
+    /// Like `execute_on_numa_nodes`, but fails with the error of the first thread that could
+    /// not be bound, see `try_bind_current_thread_to_numa_node`. `f` is not run on such threads.
+    pub fn try_execute_on_numa_nodes<R: Send, F: Fn(NumaIndex) -> R + Sync>(
+        &self, nodes: &[NumaIndex], f: F,
+    ) -> Result<Vec<R>, NumaError> {
+        #[cfg(not(target_arch = "wasm32"))]
+        return thread::scope(|scope| {
+            let handles = nodes
+                .iter()
+                .map(|&node| {
+                    let f = &f;
+                    scope.spawn(move || {
+                        self.try_bind_current_thread_to_numa_node(node)?;
+                        Ok(f(node))
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles.into_iter().map(|handle| handle.join().unwrap_or_else(|e| std::panic::resume_unwind(e))).collect()
+        });
 
-impl<T: NumaReplicable> NumaReplicated<T> {
-    pub fn new(ctx: Arc<NumaReplicationContext>) -> Arc<Self> {
-        let obj = Arc::new(Self { ctx, instances: RwLock::new(Vec::new()) });
-        obj.replicate_instances();
-        obj.ctx.attach(obj.clone());
-        obj
+        #[cfg(target_arch = "wasm32")]
+        Ok(nodes.iter().map(|&node| f(node)).collect())
     }
 
-    pub fn get(&self, token: NumaReplicatedAccessToken) -> Arc<T> {
-        self.instances.read().unwrap()[token.index].clone()
+    /// Copies `len` bytes from a buffer on `src_node` to a buffer on `dst_node`.
+    ///
+    /// The copy runs on a thread bound to the destination node, so the source is read across
+    /// the interconnect once while the writes stay local to the destination.
+    ///
+    /// # Safety
+    ///
+    /// `src` must be valid for reads and `dst` valid for writes of `len` bytes, the regions must
+    /// not overlap, and neither may be accessed by other threads for the duration of the copy.
+    pub unsafe fn copy_between_nodes(
+        &self, dst: *mut u8, dst_node: NumaIndex, src: *const u8, src_node: NumaIndex, len: usize,
+    ) {
+        assert!(dst_node < self.nodes.len() && src_node < self.nodes.len());
+
+        let (dst, src) = (dst as usize, src as usize);
+        self.execute_on_numa_node(dst_node, move || unsafe {
+            std::ptr::copy_nonoverlapping(src as *const u8, dst as *mut u8, len);
+        });
     }
 
-    pub fn all(&self) -> Vec<Arc<T>> {
-        self.instances.read().unwrap().clone()
+    /// Maps `key` to a node, so that data partitioned by key can live on a fixed node
+    /// regardless of which thread accesses it.
+    ///
+    /// The mapping only depends on the key and the number of nodes, so it is reproducible
+    /// across runs on the same topology.
+    pub fn node_for_key(&self, key: u64) -> NumaIndex {
+        // SplitMix64 finalizer, so that sequential keys spread evenly across nodes
+        let mut z = key;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^= z >> 31;
+
+        (((z as u128) * (self.nodes.len().max(1) as u128)) >> 64) as usize
     }
 
-    fn replicate_instances(&self) {
-        let cfg = self.ctx.get_numa_config();
-        let mut instances = Vec::<Arc<T>>::new();
+    /// Returns the kernel's allocation counters for every node, indexed by dense node index.
+    ///
+    /// The counters are read from `/sys/devices/system/node/node<N>/numastat` and cover all
+    /// allocations on the system since boot rather than only those of this process. Nodes whose
+    /// counters cannot be read report zeros.
+    pub fn numastat(&self) -> Vec<NodeStat> {
+        (0..self.nodes.len())
+            .map(|node| {
+                let path = format!("/sys/devices/system/node/node{}/numastat", self.os_node_id(node));
+                fs::read_to_string(path).map(|stat| parse_numastat(&stat)).unwrap_or_default()
+            })
+            .collect()
+    }
 
-        let allocate_on_node = |node| {
-            let (tx, rx) = mpsc::channel();
-            cfg.execute_on_numa_node(node, move || {
-                tx.send(T::allocate()).expect("failed to send NUMA replicated instance");
-            });
-            rx.recv().expect("failed to receive NUMA replicated instance")
+    /// Returns the free memory of `node` in bytes, read from its `meminfo` file.
+    pub fn free_memory(&self, node: NumaIndex) -> Option<usize> {
+        if node >= self.nodes.len() {
+            return None;
+        }
+
+        let path = format!("/sys/devices/system/node/node{}/meminfo", self.os_node_id(node));
+        parse_meminfo(&fs::read_to_string(path).ok()?, "MemFree")
+    }
+
+    /// Returns whether `node` has room for `bytes` while keeping `margin` bytes free.
+    pub fn can_allocate(&self, node: NumaIndex, bytes: usize, margin: usize) -> bool {
+        self.ensure_can_allocate(node, bytes, margin).is_ok()
+    }
+
+    /// Checks that `node` has room for `bytes` while keeping `margin` bytes free, so a large
+    /// allocation can fail early instead of falling back to remote memory or the OOM killer.
+    ///
+    /// The check succeeds if the free memory of the node cannot be determined.
+    pub fn ensure_can_allocate(&self, node: NumaIndex, bytes: usize, margin: usize) -> Result<(), NumaError> {
+        if node >= self.nodes.len() {
+            return Err(NumaError::OutOfRange);
+        }
+
+        match self.free_memory(node) {
+            Some(available) => check_capacity(node, bytes.saturating_add(margin), available),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns a human-readable report of the topology and the kernel settings affecting it,
+    /// meant to be pasted into bug reports.
+    pub fn summary(&self) -> String {
+        let free = (0..self.nodes.len()).map(|node| self.free_memory(node)).collect::<Vec<_>>();
+        let thp = fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled").unwrap_or_default();
+        self.format_summary(&free, auto_numa_balancing_enabled(), parse_thp_mode(&thp))
+    }
+
+    fn format_summary(&self, free: &[Option<usize>], balancing: bool, thp: Option<&str>) -> String {
+        use std::fmt::Write;
+
+        let replication = if self.requires_memory_replication() { "enabled" } else { "disabled" };
+        let mut report = format!("NUMA nodes: {} (replication {replication})\n", self.nodes.len());
+
+        for (node, cpus) in self.nodes.iter().enumerate() {
+            let memory = match free.get(node).copied().flatten() {
+                Some(bytes) => format!("{} MiB free", bytes / (1024 * 1024)),
+                None => "free memory unknown".to_string(),
+            };
+            let cpu_list = format_cpu_list(cpus);
+            let os_node = self.os_node_id(node);
+            writeln!(report, "node {node} (OS node {os_node}): {} CPUs [{cpu_list}], {memory}", cpus.len()).unwrap();
+        }
+
+        writeln!(report, "automatic NUMA balancing: {}", if balancing { "enabled" } else { "disabled" }).unwrap();
+        writeln!(report, "transparent huge pages: {}", thp.unwrap_or("unknown")).unwrap();
+        report
+    }
+
+    /// Returns the node whose CPUs have the lowest average utilization.
+    ///
+    /// The utilization is sampled from `/proc/stat` over a short window, so the result only
+    /// reflects the load at the time of the call and may already be stale when acted upon.
+    /// Falls back to node 0 when the load cannot be determined.
+    pub fn least_loaded_node(&self) -> NumaIndex {
+        #[cfg(all(target_os = "linux", not(target_os = "android")))]
+        {
+            const SAMPLE_WINDOW: std::time::Duration = std::time::Duration::from_millis(10);
+
+            let sample = || fs::read_to_string("/proc/stat").map(|stat| parse_cpu_times(&stat));
+
+            if let Ok(before) = sample() {
+                thread::sleep(SAMPLE_WINDOW);
+                if let Ok(after) = sample() {
+                    return self.least_loaded_node_between(&before, &after);
+                }
+            }
+        }
+
+        0
+    }
+
+    fn least_loaded_node_between(
+        &self, before: &BTreeMap<CpuIndex, CpuTimes>, after: &BTreeMap<CpuIndex, CpuTimes>,
+    ) -> NumaIndex {
+        let utilization = |cpu: &CpuIndex| {
+            let (before, after) = (before.get(cpu)?, after.get(cpu)?);
+            let total = after.total.saturating_sub(before.total);
+            let busy = after.busy.saturating_sub(before.busy);
+            Some(if total == 0 { 0.0 } else { busy as f64 / total as f64 })
         };
 
-        if cfg.suggests_binding_threads(self.ctx.get_thread_count()) {
-            for node in 0..cfg.num_numa_nodes() {
-                instances.push(allocate_on_node(node));
+        let mut best_node = 0;
+        let mut best_load = f64::MAX;
+
+        for (node, cpus) in self.nodes.iter().enumerate() {
+            let samples = cpus.iter().filter_map(utilization).collect::<Vec<_>>();
+            if samples.is_empty() {
+                continue;
+            }
+
+            let load = samples.iter().sum::<f64>() / samples.len() as f64;
+            if load < best_load {
+                best_node = node;
+                best_load = load;
+            }
+        }
+
+        best_node
+    }
+
+    fn add_cpu_to_node(&mut self, node: NumaIndex, cpu: CpuIndex) {
+        if self.nodes.len() <= node {
+            self.nodes.resize_with(node + 1, BTreeSet::new);
+        }
+
+        self.nodes[node].insert(cpu);
+        if self.node_by_cpu.len() <= cpu {
+            self.node_by_cpu.resize(cpu + 1, None);
+        }
+        self.node_by_cpu[cpu] = Some(node);
+        self.highest_cpu_index = self.highest_cpu_index.max(cpu);
+    }
+
+    fn node_of_cpu(&self, cpu: CpuIndex) -> Option<NumaIndex> {
+        self.node_by_cpu.get(cpu).copied().flatten()
+    }
+
+    /// Returns the node of the CPU the current thread is running on, or node 0 if it cannot
+    /// be determined. Unless the thread is bound, the answer may be stale by the time it is used.
+    pub fn current_node(&self) -> NumaIndex {
+        current_cpu().and_then(|cpu| self.node_of_cpu(cpu)).unwrap_or(0)
+    }
+
+    /// Returns the dense index used by this configuration for the OS node id `os_node`.
+    ///
+    /// Dense indices range over `0..num_numa_nodes()` and are suitable for indexing per-node
+    /// arrays, while OS node ids are the sparse ids expected by system calls. The two only
+    /// differ when the system has nodes without usable CPUs.
+    pub fn dense_index_of_node(&self, os_node: usize) -> Option<NumaIndex> {
+        (0..self.nodes.len()).find(|&node| self.os_node_id(node) == os_node)
+    }
+
+    /// Returns the OS node id of the node with dense index `index`.
+    pub fn node_of_dense_index(&self, index: NumaIndex) -> Option<usize> {
+        (index < self.nodes.len()).then(|| self.os_node_id(index))
+    }
+
+    fn os_node_id(&self, node: NumaIndex) -> usize {
+        self.os_node_ids.get(node).copied().unwrap_or(node)
+    }
+
+    fn set_distance(&mut self, from: NumaIndex, to: NumaIndex, distance: u32) {
+        let size = self.distances.len().max(from + 1).max(to + 1);
+        self.distances.resize_with(size, Vec::new);
+        for (row_node, row) in self.distances.iter_mut().enumerate() {
+            let known = row.len();
+            row.extend((known..size).map(|to| if to == row_node { LOCAL_DISTANCE } else { REMOTE_DISTANCE }));
+        }
+
+        self.distances[from][to] = distance;
+    }
+
+    /// Returns the relative distance between two nodes, using the conventional local and
+    /// remote distances if the system does not report them.
+    fn distance(&self, from: NumaIndex, to: NumaIndex) -> u32 {
+        let reported = self.distances.get(from).and_then(|row| row.get(to)).copied();
+        reported.unwrap_or(if from == to { LOCAL_DISTANCE } else { REMOTE_DISTANCE })
+    }
+
+    /// Returns the relative distance between two nodes as reported by the firmware, where the
+    /// distance of a node to itself is 10. Systems that do not report distances get 10 for the
+    /// same node and 20 for any other. Returns `None` if either node does not exist.
+    pub fn node_distance(&self, from: NumaIndex, to: NumaIndex) -> Option<u32> {
+        (from < self.nodes.len() && to < self.nodes.len()).then(|| self.distance(from, to))
+    }
+
+    /// Returns the full distance matrix, indexed by dense node index, see `node_distance`.
+    pub fn distances(&self) -> Vec<Vec<u32>> {
+        let nodes = 0..self.nodes.len();
+        nodes.clone().map(|from| nodes.clone().map(|to| self.distance(from, to)).collect()).collect()
+    }
+
+    /// Returns the node from `candidates` that is closest to `node`, preferring lower indices on ties.
+    fn closest_node(&self, node: NumaIndex, candidates: &[NumaIndex]) -> Option<NumaIndex> {
+        candidates.iter().copied().min_by_key(|&candidate| (self.distance(node, candidate), candidate))
+    }
+
+    /// Returns whether `node` has local memory. Nodes with only CPUs are served by the replica
+    /// of the nearest node that has memory.
+    pub fn has_memory(&self, node: NumaIndex) -> bool {
+        node < self.nodes.len() && !self.memoryless.contains(&node)
+    }
+
+    /// Selects at most `limit` nodes with memory to hold replicas, preferring nodes with the
+    /// most CPUs and then nodes with the lowest total distance to all other nodes. The result
+    /// is sorted by index.
+    fn select_replica_nodes(&self, limit: usize) -> Vec<NumaIndex> {
+        let mut nodes = (0..self.nodes.len()).filter(|&node| self.has_memory(node)).collect::<Vec<_>>();
+        if nodes.is_empty() {
+            nodes = (0..self.nodes.len()).collect();
+        }
+
+        if nodes.len() > limit {
+            let spread = |node: NumaIndex| (0..self.nodes.len()).map(|other| self.distance(node, other)).sum::<u32>();
+            nodes.sort_by_key(|&node| (std::cmp::Reverse(self.nodes[node].len()), spread(node), node));
+            nodes.truncate(limit.max(1));
+            nodes.sort_unstable();
+        }
+
+        nodes
+    }
+
+    fn remove_empty_numa_nodes(&mut self) {
+        let retained = (0..self.nodes.len()).filter(|&node| !self.nodes[node].is_empty()).collect::<Vec<_>>();
+        if !self.distances.is_empty() {
+            self.distances =
+                retained.iter().map(|&from| retained.iter().map(|&to| self.distance(from, to)).collect()).collect();
+        }
+
+        self.os_node_ids = retained.iter().map(|&node| self.os_node_id(node)).collect();
+        self.memoryless = (0..retained.len()).filter(|&index| self.memoryless.contains(&retained[index])).collect();
+        self.nodes.retain(|cpus| !cpus.is_empty());
+
+        let highest_cpu_index = self.nodes.iter().filter_map(|cpus| cpus.last().copied()).max();
+        self.highest_cpu_index = highest_cpu_index.unwrap_or(0);
+
+        self.node_by_cpu = vec![None; highest_cpu_index.map_or(0, |cpu| cpu + 1)];
+        for (node, cpus) in self.nodes.iter().enumerate() {
+            for &cpu in cpus {
+                self.node_by_cpu[cpu] = Some(node);
+            }
+        }
+    }
+
+    /// Returns a single node over the CPUs in `affinity`, or no nodes at all if the affinity of
+    /// the process could not be read, so that nothing is bound to CPUs it does not have.
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    fn from_affinity(affinity: &BTreeSet<CpuIndex>) -> Self {
+        let mut cfg = NumaConfig::empty();
+        for &cpu in affinity {
+            cfg.add_cpu_to_node(0, cpu);
+        }
+        cfg
+    }
+
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    fn from_system_numa(affinity: &BTreeSet<CpuIndex>) -> Self {
+        let mut cfg = NumaConfig::empty();
+
+        let fallback = || Self::from_affinity(affinity);
+
+        let node_ids = fs::read_to_string("/sys/devices/system/node/online").map(|ids| parse_cpu_list(&ids));
+        let Ok(Ok(node_ids)) = node_ids else {
+            return fallback();
+        };
+
+        if node_ids.is_empty() {
+            return fallback();
+        }
+
+        for &node in &node_ids {
+            let path = format!("/sys/devices/system/node/node{node}/distance");
+            if let Ok(distances) = fs::read_to_string(&path) {
+                let distances = distances.split_whitespace().filter_map(|d| d.parse().ok());
+                for (&to, distance) in node_ids.iter().zip(distances) {
+                    cfg.set_distance(node, to, distance);
+                }
+            }
+        }
+
+        for &node in &node_ids {
+            let path = format!("/sys/devices/system/node/node{node}/cpulist");
+            let Ok(Ok(cpu_ids)) = fs::read_to_string(&path).map(|ids| parse_cpu_list(&ids)) else {
+                return fallback();
+            };
+
+            for cpu in cpu_ids {
+                if affinity.contains(&cpu) {
+                    cfg.add_cpu_to_node(node, cpu);
+                }
+            }
+
+            let path = format!("/sys/devices/system/node/node{node}/meminfo");
+            let total = fs::read_to_string(&path).ok().and_then(|meminfo| parse_meminfo(&meminfo, "MemTotal"));
+            if total == Some(0) {
+                cfg.memoryless.insert(node);
             }
-        } else if let Some(shared) = T::allocate_shared() {
-            instances.push(shared);
-        } else {
-            instances.push(allocate_on_node(0));
         }
 
-        *self.instances.write().unwrap() = instances;
+        cfg
     }
 }
 
-impl<T: NumaReplicable> NumaReplicatedBase for NumaReplicated<T> {
-    fn on_numa_config_changed(&self) {
-        self.replicate_instances();
+/// Parses a Linux CPU list such as `0-3,8,10-11` into the CPU indices it contains,
+/// as used by sysfs files like `cpulist`, `isolated` or `thread_siblings_list`.
+///
+/// Surrounding whitespace is ignored and an empty list yields no CPUs. Indices of `MAX_CPUS`
+/// or more are rejected before any range is expanded, so a malformed list from a config file
+/// cannot exhaust memory.
+pub fn parse_cpu_list(cpu_list: &str) -> Result<Vec<CpuIndex>, NumaError> {
+    let parse = |cpu: &str| match cpu.trim().parse::<CpuIndex>() {
+        Ok(cpu) if cpu < MAX_CPUS => Ok(cpu),
+        _ => Err(NumaError::InvalidCpuList),
+    };
+
+    let mut cpus = Vec::new();
+    for segment in cpu_list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        match segment.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if first > last {
+                    return Err(NumaError::InvalidCpuList);
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(parse(segment)?),
+        }
     }
 
-    fn get_numa_config(&self) -> NumaConfig {
-        self.ctx.get_numa_config()
+    Ok(cpus)
+}
+
+#[derive(Copy, Clone, Default)]
+struct CpuTimes {
+    busy: u64,
+    total: u64,
+}
+
+/// Parses the per-CPU lines of `/proc/stat` into cumulative busy and total jiffies.
+fn parse_cpu_times(stat: &str) -> BTreeMap<CpuIndex, CpuTimes> {
+    let mut times = BTreeMap::new();
+
+    for line in stat.lines() {
+        let mut fields = line.split_whitespace();
+        let Some(cpu) = fields.next().and_then(|name| name.strip_prefix("cpu")).and_then(|id| id.parse().ok()) else {
+            continue;
+        };
+
+        let values = fields.filter_map(|v| v.parse::<u64>().ok()).collect::<Vec<_>>();
+        if values.len() < 4 {
+            continue;
+        }
+
+        // Fields: user nice system idle iowait irq softirq steal (guest time is already included in user)
+        let total = values.iter().take(8).sum::<u64>();
+        let idle = values[3] + values.get(4).copied().unwrap_or(0);
+        times.insert(cpu, CpuTimes { busy: total - idle, total });
+    }
+
+    times
+}
+
+/// Page allocation counters the kernel keeps for a single node.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeStat {
+    /// Pages allocated on this node that were intended for it.
+    pub numa_hit: u64,
+    /// Pages allocated on this node that were intended for another node.
+    pub numa_miss: u64,
+    /// Pages intended for this node that were allocated elsewhere.
+    pub numa_foreign: u64,
+    /// Interleaved pages successfully allocated on this node.
+    pub interleave_hit: u64,
+    /// Pages allocated on this node while the process was running on it.
+    pub local_node: u64,
+    /// Pages allocated on this node while the process was running on another node.
+    pub other_node: u64,
+}
+
+/// Parses the `key value` lines of a node's `numastat` file, ignoring unknown counters.
+fn parse_numastat(stat: &str) -> NodeStat {
+    let mut result = NodeStat::default();
+
+    for line in stat.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(key), Some(Ok(value))) = (fields.next(), fields.next().map(str::parse::<u64>)) else {
+            continue;
+        };
+
+        match key {
+            "numa_hit" => result.numa_hit = value,
+            "numa_miss" => result.numa_miss = value,
+            "numa_foreign" => result.numa_foreign = value,
+            "interleave_hit" => result.interleave_hit = value,
+            "local_node" => result.local_node = value,
+            "other_node" => result.other_node = value,
+            _ => {}
+        }
+    }
+
+    result
+}
+
+/// Formats CPU indices in the Linux CPU list syntax, the inverse of `parse_cpu_list`.
+fn format_cpu_list(cpus: &BTreeSet<CpuIndex>) -> String {
+    let mut ranges = Vec::<(CpuIndex, CpuIndex)>::new();
+    for &cpu in cpus {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == cpu => *last = cpu,
+            _ => ranges.push((cpu, cpu)),
+        }
+    }
+
+    let format = |&(first, last): &(CpuIndex, CpuIndex)| {
+        if first == last { first.to_string() } else { format!("{first}-{last}") }
+    };
+    ranges.iter().map(format).collect::<Vec<_>>().join(",")
+}
+
+/// Extracts the selected mode, such as `madvise` in `always [madvise] never`.
+fn parse_thp_mode(enabled: &str) -> Option<&str> {
+    let (_, rest) = enabled.split_once('[')?;
+    rest.split_once(']').map(|(mode, _)| mode)
+}
+
+/// Extracts a field such as `MemFree` of a node's `meminfo` file in bytes.
+fn parse_meminfo(meminfo: &str, field: &str) -> Option<usize> {
+    let field = format!("{field}:");
+    let line = meminfo.lines().find(|line| line.contains(&field))?;
+    let (_, value) = line.split_once(&field)?;
+    let kb = value.trim().strip_suffix("kB")?.trim().parse::<usize>().ok()?;
+    Some(kb * 1024)
+}
+
+fn check_capacity(node: NumaIndex, needed: usize, available: usize) -> Result<(), NumaError> {
+    if needed > available {
+        return Err(NumaError::InsufficientMemory { node, needed, available });
+    }
+    Ok(())
+}
+
+/// Returns the resident memory of this process on each node as `(OS node id, bytes)` pairs,
+/// sorted by node id.
+///
+/// The sizes are summed from `/proc/self/numa_maps`, so unlike `numastat` they only cover the
+/// pages of this process. Returns an empty list if the file cannot be read.
+pub fn process_rss_per_node() -> Vec<(usize, usize)> {
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    if let Ok(maps) = fs::read_to_string("/proc/self/numa_maps") {
+        return parse_numa_maps(&maps).into_iter().collect();
+    }
+
+    Vec::new()
+}
+
+/// Sums the `N<node>=<pages>` fields of every mapping in `numa_maps` into bytes per node.
+fn parse_numa_maps(maps: &str) -> BTreeMap<usize, usize> {
+    let mut bytes = BTreeMap::new();
+
+    for line in maps.lines() {
+        let fields = line.split_whitespace().filter_map(|field| field.split_once('=')).collect::<Vec<_>>();
+
+        let page_size = fields
+            .iter()
+            .find(|(key, _)| *key == "kernelpagesize_kB")
+            .and_then(|(_, value)| value.parse::<usize>().ok())
+            .map_or(4096, |kb| kb * 1024);
+
+        for (key, value) in fields {
+            let Some(Ok(node)) = key.strip_prefix('N').map(str::parse::<usize>) else {
+                continue;
+            };
+            if let Ok(pages) = value.parse::<usize>() {
+                *bytes.entry(node).or_insert(0) += pages * page_size;
+            }
+        }
+    }
+
+    bytes
+}
+
+/// Returns whether the kernel's automatic NUMA balancing is enabled.
+///
+/// When enabled, the kernel may migrate pages of replicated data away from the nodes they
+/// were allocated on. It can be disabled with `sysctl kernel.numa_balancing=0`.
+pub fn auto_numa_balancing_enabled() -> bool {
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    if let Ok(value) = fs::read_to_string("/proc/sys/kernel/numa_balancing") {
+        return parse_numa_balancing(&value);
+    }
+
+    false
+}
+
+fn parse_numa_balancing(value: &str) -> bool {
+    value.trim().parse::<u32>().is_ok_and(|mode| mode != 0)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IsolationPolicy {
+    /// Treat isolated CPUs like any other CPU.
+    Ignore,
+    /// Keep threads off isolated CPUs.
+    Avoid,
+    /// Place threads exclusively on isolated CPUs.
+    Only,
+}
+
+/// Returns the CPUs isolated from the general scheduler with the `isolcpus` boot parameter.
+pub fn isolated_cpus() -> BTreeSet<CpuIndex> {
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    if let Ok(Ok(cpu_ids)) = fs::read_to_string("/sys/devices/system/cpu/isolated").map(|ids| parse_cpu_list(&ids)) {
+        return cpu_ids.into_iter().collect();
+    }
+
+    BTreeSet::new()
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CoreClass {
+    /// The CPU belongs to the fastest class of cores in the system.
+    Performance,
+    /// The CPU is slower than the fastest cores in the system.
+    Efficiency,
+    /// The performance class of the CPU could not be determined.
+    Unknown,
+}
+
+static CPU_CAPACITIES: LazyLock<BTreeMap<CpuIndex, u32>> = LazyLock::new(read_cpu_capacities);
+
+/// Returns the performance class of `cpu` on hybrid architectures (Intel P/E cores, ARM big.LITTLE).
+///
+/// On systems where all cores are identical every CPU is reported as `Performance`.
+pub fn core_class(cpu: CpuIndex) -> CoreClass {
+    classify_core(&CPU_CAPACITIES, cpu)
+}
+
+fn classify_core(capacities: &BTreeMap<CpuIndex, u32>, cpu: CpuIndex) -> CoreClass {
+    let (Some(&capacity), Some(&highest)) = (capacities.get(&cpu), capacities.values().max()) else {
+        return CoreClass::Unknown;
+    };
+
+    if capacity == highest { CoreClass::Performance } else { CoreClass::Efficiency }
+}
+
+fn read_cpu_capacities() -> BTreeMap<CpuIndex, u32> {
+    let mut capacities = BTreeMap::new();
+
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    if let Ok(Ok(cpu_ids)) = fs::read_to_string("/sys/devices/system/cpu/online").map(|ids| parse_cpu_list(&ids)) {
+        for cpu in cpu_ids {
+            let path = format!("/sys/devices/system/cpu/cpu{cpu}/cpu_capacity");
+            if let Some(capacity) = fs::read_to_string(&path).ok().and_then(|s| parse_cpu_capacity(&s)) {
+                capacities.insert(cpu, capacity);
+            }
+        }
+    }
+
+    capacities
+}
+
+fn parse_cpu_capacity(capacity: &str) -> Option<u32> {
+    capacity.trim().parse().ok()
+}
+
+pub trait NumaReplicatedBase: Send + Sync {
+    fn on_numa_config_changed(&self);
+    fn get_numa_config(&self) -> Arc<NumaConfig>;
+}
+
+pub struct NumaReplicationContext {
+    config: RwLock<Arc<NumaConfig>>,
+    thread_count: AtomicUsize,
+    max_replicas: AtomicUsize,
+    generation: AtomicU64,
+    tracked: Mutex<Vec<Weak<dyn NumaReplicatedBase>>>,
+}
+
+impl NumaReplicationContext {
+    pub fn new(cfg: NumaConfig) -> Self {
+        Self {
+            config: RwLock::new(Arc::new(cfg)),
+            thread_count: AtomicUsize::new(1),
+            max_replicas: AtomicUsize::new(usize::MAX),
+            generation: AtomicU64::new(0),
+            tracked: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Registers `obj` to be re-replicated on configuration changes.
+    ///
+    /// Only a weak reference is kept, so the context never keeps a replicated object alive.
+    pub fn attach(&self, obj: Arc<dyn NumaReplicatedBase>) {
+        self.tracked.lock().unwrap().push(Arc::downgrade(&obj));
+    }
+
+    /// Returns the current topology. The snapshot is shared and stays valid after a later
+    /// `set_numa_config`, which installs a new configuration instead of mutating this one.
+    pub fn get_numa_config(&self) -> Arc<NumaConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Replaces the topology and re-replicates all tracked objects if it differs from the current one.
+    ///
+    /// Access tokens obtained under the previous topology must not be used afterwards.
+    pub fn set_numa_config(&self, cfg: NumaConfig) {
+        {
+            let mut config = self.config.write().unwrap();
+            if **config == cfg {
+                return;
+            }
+            *config = Arc::new(cfg);
+        }
+
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        self.notify_tracked();
+    }
+
+    /// Re-reads the topology of the system, picking up changes such as CPU hotplug
+    /// or a resized cpuset.
+    ///
+    /// Unlike a topology cached for the lifetime of the process, every replicated object
+    /// attached to this context is rebuilt for the new layout. Configurations previously
+    /// returned by `get_numa_config` keep describing the old one.
+    pub fn refresh_topology(&self) {
+        self.set_numa_config(NumaConfig::from_system());
+    }
+
+    /// Returns a counter that increases whenever the topology changes.
+    ///
+    /// Changes are only detected by explicit calls to `refresh_topology` or `set_numa_config`,
+    /// so callers can cheaply compare generations to find out whether a cached layout is stale.
+    pub fn topology_generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    pub fn set_thread_count(&self, threads: usize) {
+        let previous = self.thread_count.swap(threads, Ordering::Release);
+        if previous != threads {
+            self.notify_tracked();
+        }
+    }
+
+    pub fn get_thread_count(&self) -> usize {
+        self.thread_count.load(Ordering::Acquire)
+    }
+
+    /// Caps the number of replicas each tracked object creates.
+    ///
+    /// On machines with more nodes than the cap, replicas are placed on the best-connected
+    /// nodes and threads on the remaining nodes share the replica of their nearest replicated node.
+    pub fn set_max_replicas(&self, max_replicas: usize) {
+        let max_replicas = max_replicas.max(1);
+        let previous = self.max_replicas.swap(max_replicas, Ordering::Release);
+        if previous != max_replicas {
+            self.notify_tracked();
+        }
+    }
+
+    pub fn get_max_replicas(&self) -> usize {
+        self.max_replicas.load(Ordering::Acquire)
+    }
+
+    fn notify_tracked(&self) {
+        let tracked = {
+            let mut tracked = self.tracked.lock().unwrap();
+            tracked.retain(|obj| obj.strong_count() > 0);
+            tracked.iter().filter_map(Weak::upgrade).collect::<Vec<_>>()
+        };
+
+        for obj in tracked {
+            obj.on_numa_config_changed();
+        }
+    }
+}
+
+struct Replicas<T> {
+    instances: Vec<Arc<T>>,
+    /// The node each instance was allocated on.
+    nodes: Vec<NumaIndex>,
+    /// The index of the instance serving each node.
+    routes: Vec<usize>,
+}
+
+impl<T> Replicas<T> {
+    const fn new() -> Self {
+        Self { instances: Vec::new(), nodes: Vec::new(), routes: Vec::new() }
+    }
+
+    fn position(&self, node: NumaIndex) -> Option<usize> {
+        self.nodes.iter().position(|&n| n == node)
+    }
+}
+
+/// Groups NUMA nodes into coarser domains, such as the sockets of a multi-socket system, so
+/// that values can be replicated once per domain while threads are still bound per node.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NodeGrouping {
+    domains: Vec<usize>,
+}
+
+impl NodeGrouping {
+    /// Creates a grouping from the domain id of every node, indexed by dense node index.
+    ///
+    /// Nodes without an entry form a domain of their own.
+    pub const fn new(domains: Vec<usize>) -> Self {
+        Self { domains }
+    }
+
+    /// Groups the nodes of `cfg` by the physical package of their first CPU.
+    pub fn by_package(cfg: &NumaConfig) -> Self {
+        let package = |cpus: &BTreeSet<CpuIndex>| {
+            let cpu = cpus.first()?;
+            let path = format!("/sys/devices/system/cpu/cpu{cpu}/topology/physical_package_id");
+            fs::read_to_string(path).ok()?.trim().parse().ok()
+        };
+
+        let packages = cfg.nodes.iter().map(package).collect::<Option<Vec<usize>>>();
+        Self::new(packages.unwrap_or_else(|| (0..cfg.nodes.len()).collect()))
+    }
+
+    /// Returns the lowest node in the same domain as `node`.
+    fn representative(&self, node: NumaIndex) -> NumaIndex {
+        match self.domains.get(node) {
+            Some(domain) => self.domains.iter().position(|d| d == domain).unwrap(),
+            None => node,
+        }
+    }
+}
+
+/// A value replicated across NUMA nodes.
+///
+/// Replicated objects are shared through `Arc`; a background task that should not keep
+/// the replicas alive can hold a `Weak` obtained with `Arc::downgrade` instead.
+pub struct NumaReplicated<T: NumaReplicable> {
+    ctx: Arc<NumaReplicationContext>,
+    grouping: Option<NodeGrouping>,
+    /// The OS node ids to restrict the replicas to, see `new_on_nodes`.
+    targets: Option<Vec<usize>>,
+    concurrent: bool,
+    replicas: RwLock<Replicas<T>>,
+}
+
+impl<T: NumaReplicable> NumaReplicated<T> {
+    /// Creates the value and its replicas, failing with `NumaError::NoNodes` if the topology
+    /// of `ctx` has no nodes, or with the error of the first replica thread that could not be
+    /// bound to its node.
+    pub fn try_new(ctx: Arc<NumaReplicationContext>) -> Result<Arc<Self>, NumaError> {
+        if ctx.config.read().unwrap().num_numa_nodes() == 0 {
+            return Err(NumaError::NoNodes);
+        }
+        Self::with_layout(ctx, None, None, false)
+    }
+
+    /// Creates the value and its replicas.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a replica cannot be allocated on its node, see `try_new`. Unlike `try_new`,
+    /// a topology without nodes gets a single replica allocated on the calling thread.
+    pub fn new(ctx: Arc<NumaReplicationContext>) -> Arc<Self> {
+        Self::with_layout(ctx, None, None, false).unwrap()
+    }
+
+    /// Creates a value with one replica per domain of `grouping` instead of one per node.
+    ///
+    /// Each replica is allocated on the lowest node of its domain and serves every node in
+    /// that domain. The replica limit of the context does not apply.
+    pub fn new_per_domain(ctx: Arc<NumaReplicationContext>, grouping: NodeGrouping) -> Arc<Self> {
+        Self::with_layout(ctx, Some(grouping), None, false).unwrap()
+    }
+
+    /// Creates the value like `new`, but allocates the replicas of all nodes at the same time,
+    /// each on its own thread bound to its node. Returns once every replica is ready.
+    ///
+    /// Intended for types whose `allocate_on_node` is expensive. Later topology refreshes
+    /// rebuild the replicas concurrently as well.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the topology of `ctx` has no nodes, if a replica cannot be allocated on its
+    /// node, or if allocating any replica panics.
+    pub fn new_concurrent(ctx: Arc<NumaReplicationContext>) -> Arc<Self> {
+        Self::with_layout(ctx, None, None, true).unwrap()
+    }
+
+    /// Creates a value with replicas only on `nodes`, failing with `NumaError::OutOfRange`
+    /// if any of them does not exist, or like `try_new` if a replica cannot be allocated.
+    ///
+    /// Threads on other nodes use the replica on the nearest of `nodes`. The replica limit of
+    /// the context does not apply.
+    ///
+    /// The nodes are dense indices of the current topology, but are remembered by their OS
+    /// node id, so a topology refresh that renumbers nodes keeps the replicas on the same
+    /// physical nodes. Should a refresh remove all of them, replicas are placed as if created
+    /// with `new`.
+    pub fn new_on_nodes(ctx: Arc<NumaReplicationContext>, nodes: &[NumaIndex]) -> Result<Arc<Self>, NumaError> {
+        let cfg = ctx.get_numa_config();
+        if cfg.num_numa_nodes() == 0 {
+            return Err(NumaError::NoNodes);
+        }
+        if nodes.is_empty() || nodes.iter().any(|&node| node >= cfg.num_numa_nodes()) {
+            return Err(NumaError::OutOfRange);
+        }
+
+        let targets = nodes.iter().map(|&node| cfg.os_node_id(node)).collect();
+        Self::with_layout(ctx, None, Some(targets), false)
+    }
+
+    fn with_layout(
+        ctx: Arc<NumaReplicationContext>, grouping: Option<NodeGrouping>, targets: Option<Vec<NumaIndex>>,
+        concurrent: bool,
+    ) -> Result<Arc<Self>, NumaError> {
+        let obj = Arc::new(Self {
+            ctx,
+            grouping,
+            targets,
+            concurrent,
+            replicas: RwLock::new(Replicas::new()),
+        });
+        obj.try_replicate_instances()?;
+        obj.ctx.attach(obj.clone());
+        Ok(obj)
+    }
+
+    /// Returns the replica serving the node of `token`.
+    ///
+    /// A node without a replica of its own, for example because of `set_max_replicas`, is
+    /// served by the replica on the nearest node according to the distance matrix, preferring
+    /// the lower node index among equally distant ones.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token refers to a node that does not exist in the current topology, for
+    /// example a token kept across `set_numa_config`. Use `try_get` to handle this case.
+    pub fn get(&self, token: NumaReplicatedAccessToken) -> Arc<T> {
+        let replicas = self.replicas.read().unwrap();
+        replicas.instances[replicas.routes[token.index]].clone()
+    }
+
+    /// Returns the replica serving the node of `token`, or `None` if that node does not exist
+    /// in the current topology.
+    pub fn try_get(&self, token: NumaReplicatedAccessToken) -> Option<Arc<T>> {
+        let replicas = self.replicas.read().unwrap();
+        replicas.routes.get(token.index).map(|&index| replicas.instances[index].clone())
+    }
+
+    /// Calls `f` with the replica for `token` without cloning its `Arc`.
+    ///
+    /// Re-replication waits until `f` returns, so `f` should be short.
+    pub fn with<R>(&self, token: NumaReplicatedAccessToken, f: impl FnOnce(&T) -> R) -> R {
+        let replicas = self.replicas.read().unwrap();
+        f(&replicas.instances[replicas.routes[token.index]])
+    }
+
+    /// Returns mutable access to the replica for `token` without any synchronization, for
+    /// per-node accumulators updated only from the threads of that node.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that no other reference to this replica is used while the
+    /// returned one is alive, which includes every thread whose token routes to the same
+    /// replica and any `Arc` obtained from `get` or `all`. The topology must not change while
+    /// the reference is alive either, since re-replication frees the old replicas.
+    #[allow(clippy::mut_from_ref)]
+    pub unsafe fn get_mut_local(&self, token: NumaReplicatedAccessToken) -> &mut T {
+        let replicas = self.replicas.read().unwrap();
+        let ptr = Arc::as_ptr(&replicas.instances[replicas.routes[token.index]]) as *mut T;
+        unsafe { &mut *ptr }
+    }
+
+    /// Returns the number of replicas, which is one when nothing is replicated.
+    pub fn replica_count(&self) -> usize {
+        self.replicas.read().unwrap().instances.len()
+    }
+
+    /// Returns the nodes holding a replica in ascending order.
+    pub fn nodes(&self) -> Vec<NumaIndex> {
+        self.replicas.read().unwrap().nodes.clone()
+    }
+
+    /// Returns the CPUs served by the replica on `node`, including those of nodes without a
+    /// replica of their own that are routed to it. Empty if `node` holds no replica.
+    pub fn cpus_for_node(&self, node: NumaIndex) -> Vec<CpuIndex> {
+        let cfg = self.ctx.get_numa_config();
+        let replicas = self.replicas.read().unwrap();
+        let Some(index) = replicas.position(node) else {
+            return Vec::new();
+        };
+
+        let mut cpus = (0..cfg.nodes.len())
+            .filter(|&source| replicas.routes.get(source) == Some(&index))
+            .flat_map(|source| cfg.nodes[source].iter().copied())
+            .collect::<Vec<_>>();
+        cpus.sort_unstable();
+        cpus
+    }
+
+    /// Returns the replica allocated on `node`, or `None` if that node has no replica of its own.
+    ///
+    /// Unlike `get`, nodes without a replica are not routed to a nearby one.
+    pub fn get_for_node(&self, node: NumaIndex) -> Option<Arc<T>> {
+        let replicas = self.replicas.read().unwrap();
+        replicas.position(node).map(|index| replicas.instances[index].clone())
+    }
+
+    /// Returns an owned copy of the replica allocated on `node`, see `get_for_node`.
+    pub fn snapshot_node(&self, node: NumaIndex) -> Option<T>
+    where
+        T: Clone,
+    {
+        self.get_for_node(node).map(|instance| T::clone(&instance))
+    }
+
+    /// Returns an owned copy of every replica together with its node, in ascending node order.
+    pub fn snapshot_all(&self) -> Vec<(NumaIndex, T)>
+    where
+        T: Clone,
+    {
+        self.merge(Vec::new(), |mut acc, node, instance| {
+            acc.push((node, instance.clone()));
+            acc
+        })
+    }
+
+    pub fn all(&self) -> Vec<Arc<T>> {
+        self.iter().map(|(_, instance)| instance).collect()
+    }
+
+    /// Returns the node and value of every replica in ascending node order.
+    ///
+    /// The iterator works on a snapshot of the replica handles, which costs one allocation per
+    /// call. Holding the read guard instead would block `on_numa_config_changed` for as long as
+    /// the caller iterates; use `for_each` to visit the replicas under the lock without allocating.
+    pub fn iter(&self) -> std::vec::IntoIter<(NumaIndex, Arc<T>)> {
+        let replicas = self.replicas.read().unwrap();
+        replicas.nodes.iter().copied().zip(replicas.instances.iter().cloned()).collect::<Vec<_>>().into_iter()
+    }
+
+    /// Returns the replica serving the node that `key` maps to with `NumaConfig::node_for_key`.
+    pub fn get_for_key(&self, key: u64) -> Arc<T> {
+        let node = self.ctx.config.read().unwrap().node_for_key(key);
+        self.get(NumaReplicatedAccessToken::new(node))
+    }
+
+    /// Returns the `n`-th replica in ascending node order, wrapping around after the last one.
+    pub fn get_nth(&self, n: usize) -> Arc<T> {
+        let replicas = self.replicas.read().unwrap();
+        replicas.instances[n % replicas.instances.len()].clone()
+    }
+
+    /// Calls `f` with mutable access to the replica of `node` and returns `true`.
+    ///
+    /// Returns `false` without calling `f` if there is no replica for `node`, or if the replica
+    /// is currently shared with a reader that obtained it through `get` or `all`.
+    pub fn update_node(&self, node: NumaIndex, f: impl FnOnce(&mut T)) -> bool {
+        let mut replicas = self.replicas.write().unwrap();
+        let Some(index) = replicas.position(node) else {
+            return false;
+        };
+
+        match Arc::get_mut(&mut replicas.instances[index]) {
+            Some(instance) => {
+                f(instance);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Calls `f` with the node and the value of every replica in ascending node order,
+    /// without cloning any `Arc`.
+    pub fn for_each(&self, mut f: impl FnMut(NumaIndex, &T)) {
+        let replicas = self.replicas.read().unwrap();
+        for (&node, instance) in replicas.nodes.iter().zip(&replicas.instances) {
+            f(node, instance);
+        }
+    }
+
+    /// Folds every replica into a single value, passing the node of each replica to `fold`.
+    pub fn merge<A, F: Fn(A, NumaIndex, &T) -> A>(&self, init: A, fold: F) -> A {
+        let replicas = self.replicas.read().unwrap();
+        replicas.nodes.iter().zip(&replicas.instances).fold(init, |acc, (&node, instance)| fold(acc, node, instance))
+    }
+
+    /// Reads every cache line of each replica from a thread bound to its node, so that the pages
+    /// are resident and the caches warm before timing-sensitive work starts.
+    ///
+    /// Only the memory of the replica itself is touched, not any heap data it points to.
+    pub fn warmup(&self) {
+        let cfg = self.ctx.get_numa_config();
+        let replicas = self.replicas.read().unwrap();
+
+        // Without any node the single replica lives wherever the calling thread allocated it
+        if cfg.num_numa_nodes() == 0 {
+            for instance in &replicas.instances {
+                touch_cache_lines(&**instance);
+            }
+            return;
+        }
+
+        cfg.execute_on_numa_nodes(&replicas.nodes, |node| {
+            touch_cache_lines(&*replicas.instances[replicas.position(node).unwrap()])
+        });
+    }
+
+    /// Returns the memory occupied by all replicas together, including the reference counts
+    /// and alignment padding of each `Arc` allocation.
+    ///
+    /// Heap data owned by `T` is not included.
+    pub fn total_bytes(&self) -> usize {
+        let counts = std::alloc::Layout::new::<[AtomicUsize; 2]>();
+        let (layout, _) = counts.extend(std::alloc::Layout::new::<T>()).unwrap();
+        self.replicas.read().unwrap().instances.len() * layout.pad_to_align().size()
+    }
+
+    /// Returns the fraction of replicas whose memory does not reside on the node they were
+    /// allocated for, where `0.0` means every replica is local.
+    ///
+    /// Only the memory of the replica itself is inspected, not any heap data it points to.
+    /// Returns `None` if the placement of memory cannot be queried on this system.
+    pub fn placement_imbalance(&self) -> Option<f64> {
+        self.placement_imbalance_with(node_of_address)
+    }
+
+    /// Applies `update` to every replica, each from a thread bound to the node of that replica
+    /// so that the writes stay local.
+    ///
+    /// # Safety
+    ///
+    /// The caller must ensure that no other thread accesses any replica while the update runs.
+    pub unsafe fn broadcast<F: Fn(&mut T, NumaIndex) + Sync>(&self, update: F) {
+        let cfg = self.ctx.get_numa_config();
+        let replicas = self.replicas.read().unwrap();
+
+        for (&node, instance) in replicas.nodes.iter().zip(&replicas.instances) {
+            let ptr = Arc::as_ptr(instance) as usize;
+            if cfg.num_numa_nodes() == 0 {
+                // There is no node to bind to, so update the single replica from here
+                update(unsafe { &mut *(ptr as *mut T) }, node);
+            } else {
+                cfg.execute_on_numa_node(node, || update(unsafe { &mut *(ptr as *mut T) }, node));
+            }
+        }
+    }
+
+    fn placement_imbalance_with(&self, locate: impl Fn(*const u8) -> Option<usize>) -> Option<f64> {
+        let cfg = self.ctx.get_numa_config();
+        let replicas = self.replicas.read().unwrap();
+
+        let mut misplaced = 0;
+        for (&node, instance) in replicas.nodes.iter().zip(&replicas.instances) {
+            if locate(Arc::as_ptr(instance).cast())? != cfg.os_node_id(node) {
+                misplaced += 1;
+            }
+        }
+
+        Some(misplaced as f64 / replicas.instances.len().max(1) as f64)
+    }
+
+    /// Rebuilds all replicas for the current topology. On failure the previous replicas are kept.
+    fn try_replicate_instances(&self) -> Result<(), NumaError> {
+        let cfg = self.ctx.get_numa_config();
+        let mut replicas = Replicas::new();
+
+        if cfg.suggests_binding_threads(self.ctx.get_thread_count()) {
+            (replicas.nodes, replicas.routes) = self.replica_layout(&cfg);
+            replicas.instances = if self.concurrent {
+                cfg.try_execute_on_numa_nodes(&replicas.nodes, T::allocate_on_node)?
+            } else {
+                let mut instances = Vec::with_capacity(replicas.nodes.len());
+                for &node in &replicas.nodes {
+                    instances.extend(cfg.try_execute_on_numa_nodes(&[node], T::allocate_on_node)?);
+                }
+                instances
+            };
+        } else {
+            let home = self.target_nodes(&cfg).first().copied().unwrap_or(0);
+            // Without any node there is nothing to bind to, so allocate on the calling thread
+            let shared = match T::allocate_shared() {
+                Some(shared) => shared,
+                None if cfg.num_numa_nodes() == 0 => T::allocate_on_node(home),
+                None => cfg.try_execute_on_numa_nodes(&[home], T::allocate_on_node)?.remove(0),
+            };
+            replicas.instances.push(shared);
+            replicas.nodes.push(home);
+            replicas.routes = vec![0; cfg.num_numa_nodes().max(1)];
+        }
+
+        *self.replicas.write().unwrap() = replicas;
+        Ok(())
+    }
+
+    /// Returns the nodes to allocate replicas on and the replica index serving each node.
+    fn replica_layout(&self, cfg: &NumaConfig) -> (Vec<NumaIndex>, Vec<usize>) {
+        let serving = |node| match &self.grouping {
+            Some(grouping) => grouping.representative(node),
+            None => node,
+        };
+
+        let nodes = match &self.grouping {
+            Some(_) => (0..cfg.num_numa_nodes()).map(serving).collect::<BTreeSet<_>>().into_iter().collect(),
+            None => match self.target_nodes(cfg) {
+                targets if targets.is_empty() => cfg.select_replica_nodes(self.ctx.get_max_replicas()),
+                targets => targets,
+            },
+        };
+
+        let routes = (0..cfg.num_numa_nodes())
+            .map(|node| {
+                let target = cfg.closest_node(serving(node), &nodes).unwrap();
+                nodes.iter().position(|&n| n == target).unwrap()
+            })
+            .collect();
+
+        (nodes, routes)
+    }
+
+    /// Returns the dense indices in `cfg` of the target nodes that still exist, sorted and
+    /// deduplicated.
+    fn target_nodes(&self, cfg: &NumaConfig) -> Vec<NumaIndex> {
+        let targets = self.targets.iter().flatten().filter_map(|&os_node| cfg.dense_index_of_node(os_node));
+        targets.collect::<BTreeSet<_>>().into_iter().collect()
+    }
+}
+
+impl<T: NumaReplicable> IntoIterator for &NumaReplicated<T> {
+    type Item = (NumaIndex, Arc<T>);
+    type IntoIter = std::vec::IntoIter<(NumaIndex, Arc<T>)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<T: NumaReplicable> NumaReplicatedBase for NumaReplicated<T> {
+    fn on_numa_config_changed(&self) {
+        // A replica that cannot be rebuilt, for example because its node lost every CPU the
+        // process may use, must not take the engine down. The previous replicas keep serving,
+        // and nodes the previous layout did not have use the first of them.
+        if self.try_replicate_instances().is_err() {
+            let nodes = self.ctx.get_numa_config().num_numa_nodes().max(1);
+            self.replicas.write().unwrap().routes.resize(nodes, 0);
+        }
+    }
+
+    fn get_numa_config(&self) -> Arc<NumaConfig> {
+        self.ctx.get_numa_config()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stable_node_ordering() {
+        let mut first = NumaConfig::empty();
+        for (node, cpu) in [(0, 0), (0, 1), (2, 4), (2, 5)] {
+            first.add_cpu_to_node(node, cpu);
+        }
+        first.remove_empty_numa_nodes();
+
+        let mut second = NumaConfig::empty();
+        for (node, cpu) in [(2, 5), (0, 1), (2, 4), (0, 0)] {
+            second.add_cpu_to_node(node, cpu);
+        }
+        second.remove_empty_numa_nodes();
+
+        assert!(first == second);
+        assert_eq!(first.nodes, vec![BTreeSet::from([0, 1]), BTreeSet::from([4, 5])]);
+    }
+
+    #[test]
+    fn test_dense_node_indices() {
+        let mut cfg = NumaConfig::empty();
+        for (node, cpu) in [(0, 0), (2, 1), (5, 2), (5, 3)] {
+            cfg.add_cpu_to_node(node, cpu);
+        }
+        cfg.remove_empty_numa_nodes();
+
+        assert_eq!(cfg.num_numa_nodes(), 3);
+        for (index, os_node) in [(0, 0), (1, 2), (2, 5)] {
+            assert_eq!(cfg.node_of_dense_index(index), Some(os_node));
+            assert_eq!(cfg.dense_index_of_node(os_node), Some(index));
+        }
+
+        assert_eq!(cfg.dense_index_of_node(1), None);
+        assert_eq!(cfg.node_of_dense_index(3), None);
+    }
+
+    #[test]
+    fn test_parse_cpu_list() {
+        assert_eq!(parse_cpu_list(""), Ok(vec![]));
+        assert_eq!(parse_cpu_list("\n"), Ok(vec![]));
+        assert_eq!(parse_cpu_list("7"), Ok(vec![7]));
+        assert_eq!(parse_cpu_list("0-3,8,10-11\n"), Ok(vec![0, 1, 2, 3, 8, 10, 11]));
+        assert_eq!(parse_cpu_list(" 0 - 1 , 4 "), Ok(vec![0, 1, 4]));
+
+        assert_eq!(parse_cpu_list("8190-8191"), Ok(vec![8190, 8191]));
+        for malformed in ["a", "3-1", "1-2-3", "-1", "0,,x", "1 2", "8192", "0-99999999999"] {
+            assert_eq!(parse_cpu_list(malformed), Err(NumaError::InvalidCpuList), "{malformed}");
+        }
+    }
+
+    #[test]
+    fn test_least_loaded_node() {
+        let cfg = NumaConfig::from_nodes(&[&[0, 1], &[2, 3]]);
+
+        let before = parse_cpu_times(
+            "cpu  400 0 0 400 0 0 0 0 0 0\n\
+             cpu0 100 0 0 100 0 0 0 0 0 0\n\
+             cpu1 100 0 0 100 0 0 0 0 0 0\n\
+             cpu2 100 0 0 100 0 0 0 0 0 0\n\
+             cpu3 100 0 0 100 0 0 0 0 0 0\n",
+        );
+        let after = parse_cpu_times(
+            "cpu  700 0 0 500 0 0 0 0 0 0\n\
+             cpu0 190 0 0 110 0 0 0 0 0 0\n\
+             cpu1 180 0 0 120 0 0 0 0 0 0\n\
+             cpu2 120 0 0 180 0 0 0 0 0 0\n\
+             cpu3 110 0 0 190 0 0 0 0 0 0\n",
+        );
+
+        assert_eq!(before.len(), 4);
+        assert_eq!(cfg.least_loaded_node_between(&before, &after), 1);
+        assert_eq!(cfg.least_loaded_node_between(&after, &before), 0);
+    }
+
+    #[test]
+    fn test_parse_numa_balancing() {
+        assert!(!parse_numa_balancing("0\n"));
+        assert!(parse_numa_balancing("1\n"));
+        assert!(parse_numa_balancing("2"));
+        assert!(!parse_numa_balancing(""));
+    }
+
+    #[test]
+    fn test_core_class() {
+        assert_eq!(parse_cpu_capacity("1024\n"), Some(1024));
+        assert_eq!(parse_cpu_capacity(" 446 "), Some(446));
+        assert_eq!(parse_cpu_capacity(""), None);
+        assert_eq!(parse_cpu_capacity("fast"), None);
+
+        let capacities = BTreeMap::from([(0, 1024), (1, 1024), (2, 446), (3, 446)]);
+        assert_eq!(classify_core(&capacities, 0), CoreClass::Performance);
+        assert_eq!(classify_core(&capacities, 3), CoreClass::Efficiency);
+        assert_eq!(classify_core(&capacities, 4), CoreClass::Unknown);
+
+        let uniform = BTreeMap::from([(0, 1024), (1, 1024)]);
+        assert_eq!(classify_core(&uniform, 1), CoreClass::Performance);
+    }
+
+    #[test]
+    fn test_performance_cpus() {
+        let cfg = NumaConfig::from_nodes(&[&[0, 1, 2, 3], &[4, 5, 6, 7]]);
+        let capacities = BTreeMap::from([(0, 1024), (1, 1024), (2, 446), (3, 446)]);
+
+        let cpus = cfg.performance_cpus(0, |cpu| classify_core(&capacities, cpu));
+        assert_eq!(cpus, BTreeSet::from([0, 1]));
+
+        let cpus = cfg.performance_cpus(1, |cpu| classify_core(&capacities, cpu));
+        assert_eq!(cpus, BTreeSet::from([4, 5, 6, 7]));
+    }
+
+    #[derive(Clone, Default)]
+    struct Value(u64);
+
+    impl NumaReplicable for Value {
+        fn allocate() -> Arc<Self> {
+            Arc::new(Self::default())
+        }
+    }
+
+    #[derive(Default)]
+    struct Histogram([u64; 4]);
+
+    impl NumaReplicable for Histogram {
+        fn allocate() -> Arc<Self> {
+            Arc::new(Self::default())
+        }
+    }
+
+    fn mock_replicated<T: NumaReplicable>(cfg: NumaConfig, replicas: usize) -> NumaReplicated<T> {
+        let ctx = Arc::new(NumaReplicationContext::new(cfg));
+        let instances = std::iter::repeat_with(T::allocate).take(replicas).collect();
+        let replicas = Replicas {
+            instances,
+            nodes: (0..replicas).collect(),
+            routes: (0..replicas).collect(),
+        };
+        NumaReplicated {
+            ctx,
+            grouping: None,
+            targets: None,
+            concurrent: false,
+            replicas: RwLock::new(replicas),
+        }
+    }
+
+    /// Returns a context with `nodes` nodes over the same CPUs, so that binding to any of them
+    /// succeeds on every machine, and enough threads for replication to be suggested.
+    fn bindable_context(nodes: usize) -> Arc<NumaReplicationContext> {
+        let cpus = NumaConfig::default().cpus_of(0).unwrap().collect::<Vec<_>>();
+        let ctx = Arc::new(NumaReplicationContext::new(NumaConfig::from_nodes(&vec![cpus.as_slice(); nodes])));
+        ctx.set_thread_count(1024);
+        ctx
+    }
+
+    #[test]
+    fn test_update_node() {
+        let replicated = NumaReplicated::<Value>::new(bindable_context(3));
+        assert_eq!(replicated.nodes(), vec![0, 1, 2]);
+
+        for node in 0..3 {
+            assert!(replicated.update_node(node, |value| value.0 = 10 * node as u64));
+        }
+        assert!(!replicated.update_node(3, |_| ()));
+
+        for node in 0..3 {
+            assert_eq!(replicated.get(NumaReplicatedAccessToken::new(node)).0, 10 * node as u64);
+        }
+
+        let _reader = replicated.get(NumaReplicatedAccessToken::new(1));
+        assert!(!replicated.update_node(1, |_| ()));
+    }
+
+    #[test]
+    fn test_merge() {
+        let replicated = NumaReplicated::<Histogram>::new(bindable_context(2));
+        assert!(replicated.update_node(0, |value| value.0 = [1, 2, 0, 0]));
+        assert!(replicated.update_node(1, |value| value.0 = [0, 3, 4, 5]));
+
+        let merged = replicated.merge([0; 4], |mut acc, _, histogram| {
+            acc.iter_mut().zip(histogram.0).for_each(|(total, count)| *total += count);
+            acc
+        });
+        assert_eq!(merged, [1, 5, 4, 5]);
+
+        let nodes = replicated.merge(Vec::new(), |mut acc, node, _| {
+            acc.push(node);
+            acc
+        });
+        assert_eq!(nodes, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_on_node() {
+        let cfg = NumaConfig::from_nodes(&[&[0], &[1]]);
+
+        BOUND_NODE.set(Some(1));
+        assert!(cfg.on_node(1));
+        assert!(!cfg.on_node(0));
+
+        BOUND_NODE.set(None);
+        let current = current_cpu().and_then(|cpu| cfg.node_of_cpu(cpu));
+        assert_eq!(cfg.on_node(0), current == Some(0));
+    }
+
+    #[test]
+    fn test_select_replica_nodes() {
+        let mut cfg = NumaConfig::from_nodes(&[&[0, 1], &[2, 3], &[4], &[5, 6], &[7], &[8, 9], &[10], &[11]]);
+        for from in 0..8 {
+            for to in 0..8 {
+                let distance = if from == to {
+                    10
+                } else if from / 4 == to / 4 {
+                    16
+                } else {
+                    32
+                };
+                cfg.set_distance(from, to, distance);
+            }
+        }
+        cfg.set_distance(3, 7, 16);
+        cfg.set_distance(7, 3, 16);
+
+        let nodes = cfg.select_replica_nodes(3);
+        assert_eq!(nodes, vec![0, 1, 3]);
+
+        let routes = (0..8).map(|node| cfg.closest_node(node, &nodes).unwrap()).collect::<Vec<_>>();
+        assert_eq!(routes, vec![0, 1, 0, 3, 0, 0, 0, 3]);
+
+        assert_eq!(cfg.select_replica_nodes(usize::MAX), (0..8).collect::<Vec<_>>());
+        assert_eq!(cfg.select_replica_nodes(0).len(), 1);
+    }
+
+    #[test]
+    fn test_placement_imbalance() {
+        let replicated = mock_replicated::<Value>(NumaConfig::from_nodes(&[&[0], &[1], &[2], &[3]]), 4);
+        let placement = replicated.all().iter().map(|v| Arc::as_ptr(v) as usize).collect::<Vec<_>>();
+
+        let misplaced = |addr: *const u8| {
+            let node = placement.iter().position(|&a| a == addr as usize)?;
+            Some(if node == 2 { 0 } else { node })
+        };
+        assert_eq!(replicated.placement_imbalance_with(misplaced), Some(0.25));
+        assert_eq!(replicated.placement_imbalance_with(|_| None), None);
+    }
+
+    #[test]
+    fn test_copy_between_nodes() {
+        let cfg = NumaConfig::default();
+        let src = (0..4096).map(|i| i as u8).collect::<Vec<_>>();
+        let mut dst = vec![0u8; src.len()];
+
+        unsafe { cfg.copy_between_nodes(dst.as_mut_ptr(), 0, src.as_ptr(), 0, src.len()) };
+        assert_eq!(dst, src);
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let replicated = mock_replicated::<Value>(NumaConfig::default(), 1);
+        unsafe { replicated.broadcast(|value, node| value.0 = 42 + node as u64) };
+
+        replicated.merge((), |_, node, value| assert_eq!(value.0, 42 + node as u64));
+    }
+
+    #[test]
+    fn test_topology_generation() {
+        let ctx = NumaReplicationContext::new(NumaConfig::from_nodes(&[&[0, 1]]));
+        assert_eq!(ctx.topology_generation(), 0);
+
+        ctx.set_numa_config(NumaConfig::from_nodes(&[&[0, 1]]));
+        assert_eq!(ctx.topology_generation(), 0);
+
+        ctx.set_numa_config(NumaConfig::from_nodes(&[&[0], &[1]]));
+        assert_eq!(ctx.topology_generation(), 1);
+        assert_eq!(ctx.get_numa_config().num_numa_nodes(), 2);
+    }
+
+    #[test]
+    fn test_try_bind_out_of_range() {
+        let cfg = NumaConfig::default();
+        let nodes = cfg.num_numa_nodes();
+
+        thread::spawn(move || {
+            assert!(cfg.try_bind_current_thread_to_numa_node(nodes - 1).is_ok());
+            assert_eq!(cfg.try_bind_current_thread_to_numa_node(nodes).err(), Some(NumaError::OutOfRange));
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    fn test_bind_to_empty_node_is_noop() {
+        let mut cfg = NumaConfig::default();
+        cfg.nodes.push(BTreeSet::new());
+
+        thread::spawn(move || {
+            let original = get_process_affinity();
+            assert_eq!(cfg.try_bind_current_thread_to_numa_node(1).err(), Some(NumaError::OutOfRange));
+            assert_eq!(get_process_affinity(), original);
+            assert_eq!(BOUND_NODE.get(), None);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_execute_on_numa_nodes_concurrently() {
+        let cfg = NumaConfig::default();
+        let barrier = std::sync::Barrier::new(3);
+
+        let results = cfg.execute_on_numa_nodes(&[0, 0, 0], |node| {
+            barrier.wait();
+            node + 1
+        });
+        assert_eq!(results, vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn test_weak_replicated() {
+        let ctx = Arc::new(NumaReplicationContext::new(NumaConfig::default()));
+        let replicated = NumaReplicated::<Value>::new(ctx.clone());
+
+        let weak = Arc::downgrade(&replicated);
+        assert!(weak.upgrade().is_some());
+
+        drop(replicated);
+        assert!(weak.upgrade().is_none());
+
+        ctx.set_thread_count(2);
+        assert!(ctx.tracked.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_isolation_cpus() {
+        let isolated = parse_cpu_list("2-4,6\n").unwrap().into_iter().collect();
+        assert_eq!(isolated, BTreeSet::from([2, 3, 4, 6]));
+
+        let cfg = NumaConfig::from_nodes(&[&[0, 1, 2, 3], &[4, 5, 6, 7]]);
+        assert_eq!(cfg.isolation_cpus(0, IsolationPolicy::Avoid, &isolated), BTreeSet::from([0, 1]));
+        assert_eq!(cfg.isolation_cpus(1, IsolationPolicy::Only, &isolated), BTreeSet::from([4, 6]));
+        assert_eq!(cfg.isolation_cpus(1, IsolationPolicy::Ignore, &isolated), BTreeSet::from([4, 5, 6, 7]));
+
+        let cfg = NumaConfig::from_nodes(&[&[2, 3]]);
+        assert_eq!(cfg.isolation_cpus(0, IsolationPolicy::Avoid, &isolated), BTreeSet::from([2, 3]));
+    }
+
+    #[test]
+    fn test_get_nth() {
+        let replicated = mock_replicated::<Value>(NumaConfig::from_nodes(&[&[0], &[1], &[2]]), 3);
+        for node in 0..3 {
+            assert!(replicated.update_node(node, |value| value.0 = node as u64));
+        }
+
+        let order = replicated.merge(Vec::new(), |mut acc, node, _| {
+            acc.push(node as u64);
+            acc
+        });
+        let nth = (0..6).map(|n| replicated.get_nth(n).0).collect::<Vec<_>>();
+        assert_eq!(nth[..3], order[..]);
+        assert_eq!(nth[3..], order[..]);
+    }
+
+    #[test]
+    fn test_affinity_spec() {
+        let cfg = NumaConfig::from_nodes(&[&[0, 1], &[2, 3], &[4, 5]]);
+        let cpus = |spec: &str| spec.parse().and_then(|spec| cfg.affinity_cpus(&spec));
+
+        assert_eq!(cpus("node:1"), Ok(BTreeSet::from([2, 3])));
+        assert_eq!(cpus("node:0-1"), Ok(BTreeSet::from([0, 1, 2, 3])));
+        assert_eq!(cpus("node:0,2"), Ok(BTreeSet::from([0, 1, 4, 5])));
+        assert_eq!(cpus("cpu:4"), Ok(BTreeSet::from([4])));
+        assert_eq!(cpus(" cpu:1-2\n"), Ok(BTreeSet::from([1, 2])));
+
+        assert_eq!(cpus("node:3"), Err(NumaError::OutOfRange));
+        assert_eq!(cpus("cpu:6"), Err(NumaError::OutOfRange));
+        for invalid in ["", "node", "node:", "node:x", "socket:0", "cpu:2-1", "1", "cpu:0-99999999999", "node:0-8192"] {
+            assert_eq!(cpus(invalid), Err(NumaError::InvalidAffinitySpec), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_parse_numastat() {
+        let sample = "numa_hit 22794231\nnuma_miss 12\nnuma_foreign 7\ninterleave_hit 1019\nlocal_node 22794200\nother_node 43\n";
+
+        assert_eq!(
+            parse_numastat(sample),
+            NodeStat {
+                numa_hit: 22794231,
+                numa_miss: 12,
+                numa_foreign: 7,
+                interleave_hit: 1019,
+                local_node: 22794200,
+                other_node: 43,
+            }
+        );
+
+        let partial = parse_numastat("numa_hit 5\nnuma_migrated 3\nnuma_miss x\n");
+        assert_eq!(partial, NodeStat { numa_hit: 5, ..NodeStat::default() });
+
+        assert_eq!(NumaConfig::default().numastat().len(), 1);
+    }
+
+    #[test]
+    fn test_node_for_key() {
+        let cfg = NumaConfig::from_nodes(&[&[0], &[1], &[2], &[3]]);
+
+        let mut counts = [0usize; 4];
+        for key in 0..40_000 {
+            counts[cfg.node_for_key(key)] += 1;
+        }
+        assert!(counts.iter().all(|&count| count.abs_diff(10_000) < 500), "{counts:?}");
+
+        assert_eq!(cfg.node_for_key(0), 0);
+        assert_eq!(cfg.node_for_key(0xDEAD_BEEF), cfg.node_for_key(0xDEAD_BEEF));
+        assert_eq!(
+            (0..8).map(|key| cfg.node_for_key(key)).collect::<Vec<_>>(),
+            (0..8).map(|key| NumaConfig::from_nodes(&[&[0], &[1], &[2], &[3]]).node_for_key(key)).collect::<Vec<_>>()
+        );
+        assert_eq!(NumaConfig::default().node_for_key(u64::MAX), 0);
+
+        let replicated = mock_replicated::<Value>(cfg.clone(), 4);
+        for node in 0..4 {
+            assert!(replicated.update_node(node, |value| value.0 = node as u64));
+        }
+        for key in 0..16 {
+            assert_eq!(replicated.get_for_key(key).0, cfg.node_for_key(key) as u64);
+        }
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    fn test_with_affinity() {
+        let original = get_process_affinity();
+        let mask = BTreeSet::from([*original.iter().next_back().unwrap()]);
+
+        assert_eq!(with_affinity(&mask, get_process_affinity), mask);
+        assert_eq!(get_process_affinity(), original);
+
+        let result = std::panic::catch_unwind(|| {
+            with_affinity(&mask, || {
+                assert_eq!(get_process_affinity(), mask);
+                panic!("restore on unwind");
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(get_process_affinity(), original);
+    }
+
+    #[test]
+    fn test_total_bytes() {
+        #[repr(align(64))]
+        #[derive(Default)]
+        struct Aligned(u8);
+
+        impl NumaReplicable for Aligned {
+            fn allocate() -> Arc<Self> {
+                Arc::new(Self::default())
+            }
+        }
+
+        let cfg = NumaConfig::from_nodes(&[&[0], &[1], &[2]]);
+        assert_eq!(mock_replicated::<Aligned>(cfg.clone(), 3).total_bytes(), 3 * 128);
+        assert_eq!(mock_replicated::<Value>(cfg, 2).total_bytes(), 2 * 24);
+    }
+
+    #[test]
+    fn test_warmup() {
+        #[repr(align(64))]
+        struct Lines([u8; 1000]);
+
+        let lines = Lines([0; 1000]);
+        assert_eq!(touch_cache_lines(&lines), 16);
+        assert_eq!(touch_cache_lines(&lines.0[10..20]), 1);
+        assert_eq!(touch_cache_lines(&lines.0[60..68]), 2);
+        assert_eq!(touch_cache_lines(&()), 0);
+
+        let replicated = NumaReplicated::<Histogram>::new(Arc::new(NumaReplicationContext::new(NumaConfig::default())));
+        replicated.warmup();
+    }
+
+    #[test]
+    fn test_per_domain_layout() {
+        let cfg = NumaConfig::from_nodes(&[&[0, 1], &[2, 3], &[4, 5], &[6, 7]]);
+        let mut replicated = mock_replicated::<Value>(cfg.clone(), 1);
+
+        replicated.grouping = Some(NodeGrouping::new(vec![0, 0, 1, 1]));
+        assert_eq!(replicated.replica_layout(&cfg), (vec![0, 2], vec![0, 0, 1, 1]));
+
+        replicated.grouping = Some(NodeGrouping::new(vec![1, 0, 1, 0]));
+        assert_eq!(replicated.replica_layout(&cfg), (vec![0, 1], vec![0, 1, 0, 1]));
+
+        replicated.grouping = Some(NodeGrouping::new(vec![0, 0]));
+        assert_eq!(replicated.replica_layout(&cfg), (vec![0, 2, 3], vec![0, 0, 1, 2]));
+
+        replicated.grouping = None;
+        assert_eq!(replicated.replica_layout(&cfg), (vec![0, 1, 2, 3], vec![0, 1, 2, 3]));
+
+        assert_eq!(NodeGrouping::by_package(&NumaConfig::default()).representative(0), 0);
+    }
+
+    #[test]
+    fn test_detect_topology_drift() {
+        let cfg = NumaConfig::from_nodes(&[&[0, 1], &[2, 3]]);
+
+        assert!(!cfg.drifted_from(&NumaConfig::from_nodes(&[&[0, 1], &[2, 3]])));
+        assert!(!cfg.drifted_from(&NumaConfig::from_nodes(&[&[0, 1, 4], &[2, 3]])));
+        assert!(cfg.drifted_from(&NumaConfig::from_nodes(&[&[0], &[1, 2, 3]])));
+        assert!(cfg.drifted_from(&NumaConfig::from_nodes(&[&[0, 1], &[2]])));
+
+        let mut renumbered = NumaConfig::from_nodes(&[&[0, 1], &[2, 3]]);
+        renumbered.os_node_ids = vec![0, 2];
+        assert!(cfg.drifted_from(&renumbered));
+
+        assert!(!cfg.drifted_with(|cpu| Some(cpu / 2)));
+        assert!(cfg.drifted_with(|cpu| (cpu < 3).then_some(cpu / 2)));
+
+        assert!(!NumaConfig::from_system().detect_topology_drift());
+    }
+
+    #[test]
+    fn test_bind_throttled() {
+        use std::time::Duration;
+
+        let cfg = NumaConfig::default();
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let bound = (0..10)
+                    .filter(|_| cfg.bind_current_thread_to_numa_node_throttled(0, Duration::from_secs(60)).is_some())
+                    .count();
+                assert_eq!(bound, 1);
+
+                assert!((0..10).all(|_| cfg.bind_current_thread_to_numa_node_throttled(0, Duration::ZERO).is_some()));
+            });
+        });
+    }
+
+    #[test]
+    fn test_parse_numa_maps() {
+        let sample = "\
+5651dc445000 default file=/usr/bin/engine mapped=2 N0=2 kernelpagesize_kB=4
+7f2a40000000 bind:1 anon=512 dirty=512 N1=512 kernelpagesize_kB=2048
+7f2a80000000 interleave:0-1 anon=10 dirty=10 N0=6 N1=4 kernelpagesize_kB=4
+7ffd1c3e0000 default stack anon=3 dirty=3 N0=3
+7ffd1c400000 default
+";
+
+        let bytes = parse_numa_maps(sample);
+        assert_eq!(bytes, BTreeMap::from([(0, (2 + 6 + 3) * 4096), (1, 512 * 2048 * 1024 + 4 * 4096)]));
+        assert!(parse_numa_maps("").is_empty());
+
+        let rss = process_rss_per_node();
+        assert!(rss.is_empty() || rss.iter().any(|&(_, bytes)| bytes > 0));
+    }
+
+    #[test]
+    fn test_split_by_package() {
+        let cfg = NumaConfig::from_nodes(&[&[0, 1, 2, 3, 4, 5, 6, 7]]);
+
+        let split = cfg.split_by(|cpu| Some(if cpu % 2 == 0 { 3 } else { 1 }));
+        assert_eq!(split.nodes, vec![BTreeSet::from([1, 3, 5, 7]), BTreeSet::from([0, 2, 4, 6])]);
+        assert_eq!(split.node_of_dense_index(1), Some(0));
+        assert!(split.requires_memory_replication());
+        assert!(split.suggests_binding_threads(8));
+
+        assert!(cfg.split_by(|_| Some(0)) == cfg);
+        assert!(cfg.split_by(|cpu| (cpu < 4).then_some(0)) == cfg);
+
+        let two_nodes = NumaConfig::from_nodes(&[&[0, 1], &[2, 3]]);
+        assert!(two_nodes.split_by(Some) == two_nodes);
+    }
+
+    #[test]
+    fn test_can_allocate() {
+        let meminfo =
+            "Node 1 MemTotal:        5996280 kB\nNode 1 MemFree:         3592972 kB\nNode 1 MemUsed: 2403308 kB\n";
+        assert_eq!(parse_meminfo(meminfo, "MemFree"), Some(3592972 * 1024));
+        assert_eq!(parse_meminfo(meminfo, "MemTotal"), Some(5996280 * 1024));
+        assert_eq!(parse_meminfo("Node 0 MemTotal: 1 kB\n", "MemFree"), None);
+        assert_eq!(parse_meminfo("Node 0 MemFree: lots\n", "MemFree"), None);
+
+        assert_eq!(check_capacity(1, 1024, 4096), Ok(()));
+        assert_eq!(check_capacity(1, 4096, 4096), Ok(()));
+        assert_eq!(
+            check_capacity(1, 4097, 4096),
+            Err(NumaError::InsufficientMemory { node: 1, needed: 4097, available: 4096 })
+        );
+
+        let cfg = NumaConfig::default();
+        assert!(cfg.can_allocate(0, 4096, 0));
+        assert!(!cfg.can_allocate(0, usize::MAX, 1) || cfg.free_memory(0).is_none());
+        assert_eq!(cfg.ensure_can_allocate(1, 0, 0), Err(NumaError::OutOfRange));
+    }
+
+    #[test]
+    fn test_try_new() {
+        let ctx = Arc::new(NumaReplicationContext::new(NumaConfig::empty()));
+        assert!(matches!(NumaReplicated::<Value>::try_new(ctx), Err(NumaError::NoNodes)));
+
+        let ctx = Arc::new(NumaReplicationContext::new(NumaConfig::default()));
+        assert_eq!(NumaReplicated::<Value>::try_new(ctx).unwrap().all().len(), 1);
+
+        let error: Box<dyn std::error::Error> =
+            Box::new(NumaError::InsufficientMemory { node: 1, needed: 8, available: 4 });
+        assert_eq!(error.to_string(), "NUMA node 1 has 4 bytes free but 8 bytes are needed");
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut cfg = NumaConfig::from_nodes(&[&[0, 1, 2, 3, 8], &[4, 5, 6, 7, 10]]);
+        cfg.os_node_ids = vec![0, 2];
+
+        assert_eq!(
+            cfg.format_summary(&[Some(3 << 30), None], false, parse_thp_mode("always [madvise] never\n")),
+            "\
+NUMA nodes: 2 (replication enabled)
+node 0 (OS node 0): 5 CPUs [0-3,8], 3072 MiB free
+node 1 (OS node 2): 5 CPUs [4-7,10], free memory unknown
+automatic NUMA balancing: disabled
+transparent huge pages: madvise
+"
+        );
+
+        assert_eq!(
+            NumaConfig::from_nodes(&[&[0]]).format_summary(&[], true, None),
+            "\
+NUMA nodes: 1 (replication disabled)
+node 0 (OS node 0): 1 CPUs [0], free memory unknown
+automatic NUMA balancing: enabled
+transparent huge pages: unknown
+"
+        );
+
+        assert_eq!(parse_cpu_list(&format_cpu_list(&BTreeSet::from([0, 2, 3, 4, 9]))), Ok(vec![0, 2, 3, 4, 9]));
+        assert!(NumaConfig::default().summary().starts_with("NUMA nodes: 1"));
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    fn test_inherited_affinity() {
+        let cfg = NumaConfig::default();
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                cfg.bind_current_thread_to_numa_node(0);
+
+                thread::scope(|scope| {
+                    scope.spawn(|| {
+                        assert_eq!(BOUND_NODE.get(), None);
+                        assert_eq!(get_process_affinity(), cfg.nodes[0]);
+                        assert_eq!(cfg.adopt_inherited_node().map(|token| token.index), Some(0));
+                        assert!(cfg.on_node(0));
+                    });
+                });
+            });
+        });
+    }
+
+    #[test]
+    fn test_topology_accessors() {
+        let cfg = NumaConfig::from_nodes(&[&[3, 1], &[0, 2]]);
+
+        assert_eq!(cfg.nodes().collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(cfg.cpus_of(0).unwrap().collect::<Vec<_>>(), vec![1, 3]);
+        assert_eq!(cfg.cpus_of(1).unwrap().collect::<Vec<_>>(), vec![0, 2]);
+        assert!(cfg.cpus_of(2).is_none());
+
+        let system = NumaConfig::default();
+        assert_eq!(system.nodes().count(), 1);
+        assert_eq!(system.cpus_of(0).unwrap().count(), *SYSTEM_THREADS);
+    }
+
+    #[test]
+    fn test_get_for_node() {
+        let mut replicated = mock_replicated::<Value>(NumaConfig::from_nodes(&[&[0], &[1], &[2], &[3], &[4], &[5]]), 3);
+        replicated.replicas.get_mut().unwrap().nodes = vec![0, 2, 5];
+        for node in [0, 2, 5] {
+            assert!(replicated.update_node(node, |value| value.0 = node as u64 + 1));
+        }
+
+        assert_eq!(replicated.get_for_node(0).unwrap().0, 1);
+        assert_eq!(replicated.get_for_node(2).unwrap().0, 3);
+        assert_eq!(replicated.get_for_node(5).unwrap().0, 6);
+        assert!(replicated.get_for_node(1).is_none());
+        assert!(replicated.get_for_node(3).is_none());
+    }
+
+    #[test]
+    fn test_system_topology_is_usable() {
+        let cfg = NumaConfig::from_system();
+        assert!(cfg.num_numa_nodes() >= 1);
+        assert!(cfg.nodes.iter().all(|cpus| !cpus.is_empty()));
+        assert_eq!(NumaConfig::is_available(), std::path::Path::new("/sys/devices/system/node/online").exists());
+
+        let ctx = Arc::new(NumaReplicationContext::new(cfg));
+        assert!(NumaReplicated::<Value>::try_new(ctx).is_ok());
+    }
+
+    #[test]
+    fn test_node_of_cpu() {
+        let mut cfg = NumaConfig::from_nodes(&[&[0, 5], &[], &[2, 9]]);
+        cfg.remove_empty_numa_nodes();
+
+        let expected = [Some(0), None, Some(1), None, None, Some(0), None, None, None, Some(1), None];
+        assert_eq!((0..11).map(|cpu| cfg.node_of_cpu(cpu)).collect::<Vec<_>>(), expected);
+
+        let mut empty = NumaConfig::empty();
+        empty.remove_empty_numa_nodes();
+        assert!(empty == NumaConfig::empty());
+    }
+
+    #[test]
+    fn test_shared_numa_config() {
+        let ctx = NumaReplicationContext::new(NumaConfig::from_nodes(&[&[0, 1]]));
+        let before = ctx.get_numa_config();
+        assert!(Arc::ptr_eq(&before, &ctx.get_numa_config()));
+
+        ctx.set_numa_config(NumaConfig::from_nodes(&[&[0], &[1]]));
+        assert_eq!(before.num_numa_nodes(), 1);
+        assert_eq!(ctx.get_numa_config().num_numa_nodes(), 2);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    fn test_scoped_bind() {
+        let cfg = NumaConfig::default();
+
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let original = get_process_affinity();
+
+                {
+                    let _guard = cfg.bind_current_thread_to_numa_node_scoped(0);
+                    assert_eq!(BOUND_NODE.get(), Some(0));
+                    assert_eq!(get_process_affinity(), cfg.nodes[0]);
+                }
+
+                assert_eq!(BOUND_NODE.get(), None);
+                assert_eq!(get_process_affinity(), original);
+            });
+        });
+    }
+
+    #[test]
+    fn test_current_node() {
+        assert_eq!(NumaConfig::default().current_node(), 0);
+        assert_eq!(NumaConfig::empty().current_node(), 0);
+
+        let cfg = NumaConfig::from_system();
+        assert!(cfg.current_node() < cfg.num_numa_nodes());
+    }
+
+    #[test]
+    fn test_with() {
+        let replicated = mock_replicated::<Value>(NumaConfig::from_nodes(&[&[0], &[1]]), 2);
+        assert!(replicated.update_node(1, |value| value.0 = 7));
+
+        assert_eq!(replicated.with(NumaReplicatedAccessToken::new(0), |value| value.0), 0);
+        assert_eq!(replicated.with(NumaReplicatedAccessToken::new(1), |value| value.0 * 2), 14);
+        assert_eq!(Arc::strong_count(&replicated.get_for_node(1).unwrap()), 2);
+    }
+
+    #[test]
+    fn test_replica_counts() {
+        let mut replicated = mock_replicated::<Value>(NumaConfig::from_nodes(&[&[0, 4], &[1, 5], &[2], &[3]]), 2);
+        let replicas = replicated.replicas.get_mut().unwrap();
+        replicas.nodes = vec![0, 2];
+        replicas.routes = vec![0, 1, 1, 0];
+
+        assert_eq!(replicated.replica_count(), 2);
+        assert_eq!(replicated.nodes(), vec![0, 2]);
+        assert_eq!(replicated.cpus_for_node(0), vec![0, 3, 4]);
+        assert_eq!(replicated.cpus_for_node(2), vec![1, 2, 5]);
+        assert!(replicated.cpus_for_node(1).is_empty());
+
+        let single = NumaReplicated::<Value>::new(Arc::new(NumaReplicationContext::new(NumaConfig::default())));
+        assert_eq!(single.replica_count(), 1);
+        assert_eq!(single.cpus_for_node(0).len(), *SYSTEM_THREADS);
+    }
+
+    #[test]
+    fn test_replicable_atomics() {
+        use std::sync::atomic::AtomicU32;
+
+        let ctx = Arc::new(NumaReplicationContext::new(NumaConfig::default()));
+
+        let counter = NumaReplicated::<AtomicU64>::new(ctx.clone());
+        counter.get(NumaReplicatedAccessToken::new(0)).fetch_add(3, Ordering::Relaxed);
+        assert_eq!(counter.merge(0, |sum, _, value| sum + value.load(Ordering::Relaxed)), 3);
+
+        let table = NumaReplicated::<[AtomicU32; 64]>::new(ctx);
+        assert!(table.get(NumaReplicatedAccessToken::new(0)).iter().all(|v| v.load(Ordering::Relaxed) == 0));
+    }
+
+    #[test]
+    fn test_node_distance() {
+        let mut cfg = NumaConfig::from_nodes(&[&[0], &[1], &[2]]);
+        assert_eq!(cfg.distances(), vec![vec![10, 20, 20], vec![20, 10, 20], vec![20, 20, 10]]);
+
+        cfg.set_distance(0, 2, 32);
+        cfg.set_distance(2, 0, 32);
+        assert_eq!(cfg.node_distance(0, 2), Some(32));
+        assert_eq!(cfg.node_distance(1, 1), Some(10));
+        assert_eq!(cfg.node_distance(0, 3), None);
+        assert_eq!(cfg.distances()[2], vec![32, 20, 10]);
+    }
+
+    #[test]
+    fn test_get_mut_local() {
+        let cfg = NumaConfig::default();
+        let replicated = NumaReplicated::<Value>::new(Arc::new(NumaReplicationContext::new(cfg.clone())));
+
+        // A single thread per replica keeps the access to each replica exclusive
+        let nodes = replicated.nodes();
+        cfg.execute_on_numa_nodes(&nodes, |node| {
+            let token = NumaReplicatedAccessToken::new(node);
+            for _ in 0..1000 {
+                unsafe { replicated.get_mut_local(token).0 += 1 };
+            }
+        });
+
+        assert_eq!(replicated.merge(0, |sum, _, value| sum + value.0), 1000 * nodes.len() as u64);
+    }
+
+    #[test]
+    fn test_allocate_on_node() {
+        struct Stamped(NumaIndex);
+
+        impl NumaReplicable for Stamped {
+            fn allocate() -> Arc<Self> {
+                unreachable!()
+            }
+
+            fn allocate_on_node(node: usize) -> Arc<Self> {
+                Arc::new(Self(node + 100))
+            }
+        }
+
+        let replicated = NumaReplicated::<Stamped>::new(Arc::new(NumaReplicationContext::new(NumaConfig::default())));
+        assert_eq!(replicated.get(NumaReplicatedAccessToken::new(0)).0, 100);
+        assert_eq!(Value::allocate_on_node(3).0, 0);
+    }
+
+    #[test]
+    fn test_from_nodes() {
+        let cfg = NumaConfig::from_nodes(&[&[4, 5], &[0, 1], &[]]);
+        assert_eq!(cfg.num_numa_nodes(), 3);
+        assert_eq!(cfg.cpus_of(0).unwrap().collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!(cfg.node_of_cpu(0), Some(1));
+        assert_eq!(cfg.cpus_of(2).unwrap().count(), 0);
+        assert_eq!(cfg.distribute_threads_among_numa_nodes(4), vec![0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_try_get() {
+        let replicated = mock_replicated::<Value>(NumaConfig::from_nodes(&[&[0], &[1]]), 2);
+        assert!(replicated.update_node(1, |value| value.0 = 5));
+
+        assert_eq!(replicated.try_get(NumaReplicatedAccessToken::new(1)).unwrap().0, 5);
+        assert!(replicated.try_get(NumaReplicatedAccessToken::new(2)).is_none());
+        assert!(std::panic::catch_unwind(|| replicated.get(NumaReplicatedAccessToken::new(2))).is_err());
+    }
+
+    #[test]
+    fn test_for_each() {
+        let replicated = mock_replicated::<AtomicU64>(NumaConfig::from_nodes(&[&[0], &[1], &[2]]), 3);
+        for node in 0..3 {
+            replicated.get(NumaReplicatedAccessToken::new(node)).fetch_add(10 * node as u64 + 1, Ordering::Relaxed);
+        }
+
+        let mut visited = Vec::new();
+        let mut total = 0;
+        replicated.for_each(|node, counter| {
+            visited.push(node);
+            total += counter.load(Ordering::Relaxed);
+        });
+
+        assert_eq!(visited, vec![0, 1, 2]);
+        assert_eq!(total, 33);
+    }
+
+    #[test]
+    fn test_nearest_replica_fallback() {
+        let mut cfg = NumaConfig::from_nodes(&[&[0, 1, 2], &[3], &[4, 5, 6], &[7]]);
+        for (from, to, distance) in [(0, 1, 12), (0, 2, 30), (0, 3, 30), (1, 2, 28), (1, 3, 40), (2, 3, 12)] {
+            cfg.set_distance(from, to, distance);
+            cfg.set_distance(to, from, distance);
+        }
+
+        let replicated = mock_replicated::<Value>(cfg.clone(), 1);
+        replicated.ctx.set_max_replicas(2);
+        assert_eq!(replicated.replica_layout(&cfg), (vec![0, 2], vec![0, 0, 1, 1]));
+
+        cfg.set_distance(1, 2, 11);
+        assert_eq!(replicated.replica_layout(&cfg), (vec![0, 2], vec![0, 1, 1, 1]));
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    fn test_bind_to_cpu() {
+        let cfg = NumaConfig::default();
+        let cpu = *cfg.nodes[0].last().unwrap();
+
+        thread::spawn(move || {
+            assert_eq!(cfg.bind_current_thread_to_cpu(cpu).map(|token| token.index), Ok(0));
+            assert_eq!(get_process_affinity(), BTreeSet::from([cpu]));
+            assert_eq!(current_cpu(), Some(cpu));
+
+            assert_eq!(cfg.bind_current_thread_to_cpu(cpu + 1).err(), Some(NumaError::OutOfRange));
+            assert_eq!(get_process_affinity(), BTreeSet::from([cpu]));
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_memoryless_nodes() {
+        let mut cfg = NumaConfig::from_nodes(&[&[0, 1], &[], &[2, 3, 4], &[5, 6]]);
+        cfg.memoryless.insert(2);
+        cfg.remove_empty_numa_nodes();
+
+        assert!(cfg.has_memory(0) && !cfg.has_memory(1) && cfg.has_memory(2));
+        assert!(!cfg.has_memory(3));
+        assert_eq!(cfg.select_replica_nodes(usize::MAX), vec![0, 2]);
+        assert_eq!(cfg.select_replica_nodes(1), vec![0]);
+
+        let replicated = mock_replicated::<Value>(cfg.clone(), 1);
+        assert_eq!(replicated.replica_layout(&cfg), (vec![0, 2], vec![0, 0, 1]));
+
+        cfg.memoryless = BTreeSet::from([0, 1, 2]);
+        assert_eq!(cfg.select_replica_nodes(usize::MAX), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_refresh_topology() {
+        let system = NumaConfig::from_system();
+        let mut seeded = system.clone();
+        seeded.add_cpu_to_node(system.num_numa_nodes(), 4096);
+
+        let ctx = Arc::new(NumaReplicationContext::new(seeded));
+        let replicated = NumaReplicated::<Value>::new(ctx.clone());
+        let before = replicated.get(NumaReplicatedAccessToken::new(0));
+        let stale = ctx.get_numa_config();
+
+        ctx.refresh_topology();
+        assert_eq!(ctx.topology_generation(), 1);
+        assert!(*ctx.get_numa_config() == system);
+        assert_eq!(stale.num_numa_nodes(), system.num_numa_nodes() + 1);
+
+        assert!(replicated.try_get(NumaReplicatedAccessToken::new(system.num_numa_nodes())).is_none());
+        assert!(!Arc::ptr_eq(&replicated.get(NumaReplicatedAccessToken::new(0)), &before));
+    }
+
+    #[test]
+    fn test_physical_cpus_of() {
+        let cfg = NumaConfig::from_nodes(&[&[0, 1, 4, 5], &[2, 3, 6, 7, 8]]);
+
+        // Cores pair CPUs n and n + 4, CPU 8 has no sibling information
+        let siblings = |cpu: CpuIndex| (cpu < 8).then(|| vec![cpu % 4, cpu % 4 + 4]);
+
+        assert_eq!(cfg.physical_cpus_with(0, siblings), vec![0, 1]);
+        assert_eq!(cfg.physical_cpus_with(1, siblings), vec![2, 3, 8]);
+        assert!(cfg.physical_cpus_with(2, siblings).is_empty());
+
+        // Siblings outside the node do not hide a CPU
+        let split = NumaConfig::from_nodes(&[&[0, 1], &[4, 5]]);
+        assert_eq!(split.physical_cpus_with(1, siblings), vec![4, 5]);
+
+        let system = NumaConfig::default();
+        assert!(!system.physical_cpus_of(0).is_empty());
+    }
+
+    #[test]
+    fn test_distribute_threads_weighted() {
+        let cfg = NumaConfig::from_nodes(&[&[0, 1, 2, 3], &[4], &[5]]);
+
+        assert_eq!(cfg.distribute_threads_weighted(6, &[1.0, 2.0, 0.0]), vec![1, 0, 1, 1, 0, 1]);
+        assert_eq!(cfg.distribute_threads_weighted(4, &[0.0, 0.0, 3.0]), vec![2; 4]);
+        assert_eq!(cfg.distribute_threads_weighted(7, &[]), vec![0, 1, 2, 0, 1, 2, 0]);
+        assert_eq!(cfg.distribute_threads_weighted(3, &[0.0, f64::NAN, -1.0]), vec![0, 1, 2]);
+
+        let counts = cfg.distribute_threads_weighted(100, &[3.0, 1.0]).iter().fold([0; 3], |mut counts, &node| {
+            counts[node] += 1;
+            counts
+        });
+        assert_eq!(counts, [75, 25, 0]);
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut replicated = mock_replicated::<Value>(NumaConfig::from_nodes(&[&[0], &[1], &[2], &[3]]), 2);
+        replicated.replicas.get_mut().unwrap().nodes = vec![1, 3];
+        assert!(replicated.update_node(1, |value| value.0 = 10));
+        assert!(replicated.update_node(3, |value| value.0 = 30));
+
+        let mut pairs = Vec::new();
+        for (node, replica) in &replicated {
+            pairs.push((node, replica.0));
+        }
+        assert_eq!(pairs, vec![(1, 10), (3, 30)]);
+        assert_eq!(replicated.iter().map(|(_, replica)| replica.0).sum::<u64>(), 40);
+    }
+
+    #[test]
+    fn test_target_nodes_layout() {
+        let cfg = NumaConfig::from_nodes(&[&[0, 1], &[2, 3], &[4, 5], &[6, 7]]);
+        let mut replicated = mock_replicated::<Value>(cfg.clone(), 1);
+
+        replicated.targets = Some(vec![3, 1, 3]);
+        assert_eq!(replicated.replica_layout(&cfg), (vec![1, 3], vec![0, 0, 0, 1]));
+
+        replicated.targets = Some(vec![2, 7]);
+        let cfg = NumaConfig::from_nodes(&[&[0, 1], &[2, 3], &[4, 5]]);
+        assert_eq!(replicated.replica_layout(&cfg), (vec![2], vec![0, 0, 0]));
+
+        replicated.targets = Some(vec![7]);
+        assert_eq!(replicated.replica_layout(&cfg), (vec![0, 1, 2], vec![0, 1, 2]));
+
+        let ctx = Arc::new(NumaReplicationContext::new(NumaConfig::default()));
+        assert_eq!(NumaReplicated::<Value>::new_on_nodes(ctx.clone(), &[1]).err(), Some(NumaError::OutOfRange));
+        assert_eq!(NumaReplicated::<Value>::new_on_nodes(ctx.clone(), &[]).err(), Some(NumaError::OutOfRange));
+        assert_eq!(NumaReplicated::<Value>::new_on_nodes(ctx, &[0]).unwrap().nodes(), vec![0]);
+
+        // Removing the empty node renumbers OS node 2 to dense index 1
+        let cpus = NumaConfig::default().cpus_of(0).unwrap().collect::<Vec<_>>();
+        let cfg = NumaConfig::from_nodes(&[&cpus, &[], &cpus]);
+        let mut refreshed = cfg.clone();
+        refreshed.remove_empty_numa_nodes();
+
+        let ctx = Arc::new(NumaReplicationContext::new(cfg));
+        ctx.set_thread_count(1024);
+        let replicated = NumaReplicated::<Value>::new_on_nodes(ctx.clone(), &[2]).unwrap();
+        assert_eq!(replicated.nodes(), vec![2]);
+
+        ctx.set_numa_config(refreshed);
+        assert_eq!(replicated.nodes(), vec![1]);
+        assert_eq!(ctx.get_numa_config().node_of_dense_index(1), Some(2));
+    }
+
+    #[test]
+    fn test_spawn_bound() {
+        let cfg = Arc::new(NumaConfig::default());
+
+        let handle = cfg.spawn_bound(0, "bound", |token| (thread::current().name().map(str::to_owned), token)).unwrap();
+        assert_eq!(handle.thread().name(), Some("bound"));
+        let (name, token) = handle.join().unwrap();
+        assert_eq!(name.as_deref(), Some("bound"));
+        assert_eq!(token.index, 0);
+
+        let err = cfg.spawn_bound(1, "bound", |_| ()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        let cpus = NumaConfig::default().cpus_of(0).unwrap().collect::<Vec<_>>();
+        let cfg = Arc::new(NumaConfig::from_nodes(&[&cpus, &[]]));
+        let err = cfg.spawn_bound(1, "bound", |_| unreachable!()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        #[cfg(all(target_os = "linux", not(target_os = "android")))]
+        {
+            let cfg = Arc::new(NumaConfig::from_nodes(&[&[1024]]));
+            let err = cfg.spawn_bound(0, "bound", |_| unreachable!()).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::Other);
+            let inner = err.into_inner().unwrap().downcast::<NumaError>().unwrap();
+            assert_eq!(*inner, NumaError::BindFailed { errno: libc::EINVAL });
+        }
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    fn test_bind_failure_is_reported() {
+        let cfg = NumaConfig::from_nodes(&[&[1000]]);
+        thread::spawn(move || {
+            let err = cfg.try_bind_current_thread_to_numa_node(0).err();
+            assert_eq!(err, Some(NumaError::BindFailed { errno: libc::EINVAL }));
+            assert_eq!(cfg.bind_current_thread_to_cpu(1000).err(), err);
+            assert_eq!(BOUND_NODE.get(), None);
+
+            let cfg = NumaConfig::from_nodes(&[&[0, 1024]]);
+            assert_eq!(cfg.try_bind_current_thread_to_numa_node(0).err(), err);
+            assert_eq!(cfg.bind_current_thread_to_cpu(1024).err(), err);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let replicated = NumaReplicated::<Value>::new_on_nodes(bindable_context(3), &[0, 2]).unwrap();
+        for node in [0, 2] {
+            assert!(replicated.update_node(node, |value| value.0 = 10 + node as u64));
+        }
+
+        let mut snapshot = replicated.snapshot_node(2).unwrap();
+        snapshot.0 += 1;
+        assert_eq!((snapshot.0, replicated.get_for_node(2).unwrap().0), (13, 12));
+        assert!(replicated.snapshot_node(1).is_none());
+
+        let all = replicated.snapshot_all().into_iter().map(|(node, value)| (node, value.0)).collect::<Vec<_>>();
+        assert_eq!(all, vec![(0, 10), (2, 12)]);
+    }
+
+    #[test]
+    fn test_empty_topology() {
+        let cfg = NumaConfig::from_nodes(&[]);
+
+        assert_eq!(cfg.distribute_threads_among_numa_nodes(3), vec![0; 3]);
+        assert_eq!(cfg.distribute_threads_weighted(3, &[1.0]), vec![0; 3]);
+        assert!(!cfg.suggests_binding_threads(3));
+        assert_eq!(cfg.node_for_key(42), 0);
+
+        thread::spawn(move || {
+            assert_eq!(cfg.try_bind_current_thread_to_numa_node(0).err(), Some(NumaError::OutOfRange))
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_replication() {
+        use std::{sync::Condvar, time::Duration};
+
+        static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+        static MAX_IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+        struct Sequential;
+
+        impl NumaReplicable for Sequential {
+            fn allocate() -> Arc<Self> {
+                Arc::new(Self)
+            }
+
+            fn allocate_on_node(_node: usize) -> Arc<Self> {
+                let in_flight = IN_FLIGHT.fetch_add(1, Ordering::SeqCst) + 1;
+                MAX_IN_FLIGHT.fetch_max(in_flight, Ordering::SeqCst);
+                IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+                Arc::new(Self)
+            }
+        }
+
+        static ARRIVED: Mutex<usize> = Mutex::new(0);
+        static ALL_ARRIVED: Condvar = Condvar::new();
+        static MET: AtomicUsize = AtomicUsize::new(0);
+
+        struct Concurrent;
+
+        impl NumaReplicable for Concurrent {
+            fn allocate() -> Arc<Self> {
+                Arc::new(Self)
+            }
+
+            // A barrier for both replicas, which only opens if they are allocated at the same
+            // time. The timeout turns a sequential allocation into a failure instead of a hang.
+            fn allocate_on_node(_node: usize) -> Arc<Self> {
+                let mut arrived = ARRIVED.lock().unwrap();
+                *arrived += 1;
+                ALL_ARRIVED.notify_all();
+
+                let (_arrived, result) =
+                    ALL_ARRIVED.wait_timeout_while(arrived, Duration::from_secs(10), |arrived| *arrived < 2).unwrap();
+                if !result.timed_out() {
+                    MET.fetch_add(1, Ordering::SeqCst);
+                }
+                Arc::new(Self)
+            }
+        }
+
+        let ctx = bindable_context(2);
+
+        assert_eq!(NumaReplicated::<Sequential>::new(ctx.clone()).replica_count(), 2);
+        assert_eq!(MAX_IN_FLIGHT.load(Ordering::SeqCst), 1);
+
+        assert_eq!(NumaReplicated::<Concurrent>::new_concurrent(ctx).replica_count(), 2);
+        assert_eq!(MET.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    fn test_try_new_reports_bind_failure() {
+        let ctx = Arc::new(NumaReplicationContext::new(NumaConfig::from_nodes(&[&[1024], &[2048]])));
+        ctx.set_thread_count(4);
+
+        let err = Some(NumaError::BindFailed { errno: libc::EINVAL });
+        assert_eq!(NumaReplicated::<Value>::try_new(ctx.clone()).err(), err);
+        assert_eq!(NumaReplicated::<Value>::new_on_nodes(ctx, &[1]).err(), err);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    fn test_unknown_affinity_fallback() {
+        let affinity = get_process_affinity();
+        let cfg = NumaConfig::from_affinity(&affinity);
+        assert_eq!(cfg.cpus_of(0).unwrap().collect::<BTreeSet<_>>(), affinity);
+        assert_eq!(NumaConfig::from_affinity(&BTreeSet::new()).num_numa_nodes(), 0);
+
+        let replicated = NumaReplicated::<Value>::new(Arc::new(NumaReplicationContext::new(NumaConfig::empty())));
+        assert_eq!(replicated.replica_count(), 1);
+
+        thread::spawn(|| {
+            drop(AffinityGuard {
+                cpus: BTreeSet::new(),
+                node: Some(1),
+                _not_send: std::marker::PhantomData,
+            });
+            assert_eq!(BOUND_NODE.get(), Some(1));
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", not(target_os = "android")))]
+    fn test_refresh_after_affinity_shrinks() {
+        let cpu = *get_process_affinity().first().unwrap();
+        let shrunk = with_affinity(&BTreeSet::from([cpu]), NumaConfig::from_system);
+        assert!(shrunk.nodes.iter().flatten().all(|&c| c == cpu));
+
+        let ctx = bindable_context(2);
+        let replicated = NumaReplicated::<Value>::new(ctx.clone());
+        assert!(replicated.update_node(1, |value| value.0 = 7));
+
+        // Neither node has a CPU the process may use any more, so nothing can be rebuilt
+        ctx.set_numa_config(NumaConfig::from_nodes(&[&[1024], &[1025], &[1026]]));
+        assert_eq!(replicated.nodes(), vec![0, 1]);
+        assert_eq!(replicated.get(NumaReplicatedAccessToken::new(1)).0, 7);
+        assert_eq!(replicated.get(NumaReplicatedAccessToken::new(2)).0, 0);
+    }
+
+    #[test]
+    fn test_no_nodes_runs_on_calling_thread() {
+        let replicated = NumaReplicated::<Value>::new(Arc::new(NumaReplicationContext::new(NumaConfig::empty())));
+        replicated.warmup();
+
+        unsafe { replicated.broadcast(|value, node| value.0 = 5 + node as u64) };
+        assert_eq!(replicated.get(NumaReplicatedAccessToken::new(0)).0, 5);
     }
 }
@@ -81,7 +81,7 @@ pub fn bench<const PRETTY: bool>(args: &[&str]) {
     let depth = args.get(2).and_then(|v| v.parse().ok()).unwrap_or(DEFAULT_DEPTH);
 
     let shared = Arc::new(SharedContext::default());
-    shared.tt.resize(threads, hash);
+    shared.tt.resize(threads, &shared.numa_context.get_numa_config(), hash);
 
     let mut pool = ThreadPool::new(shared.clone());
     pool.set_count(threads);
@@ -83,7 +83,7 @@ pub fn speedtest(args: &[&str]) {
     println!("Hash (MiB):        {hash}");
 
     let shared = Arc::new(SharedContext::default());
-    shared.tt.resize(threads, hash);
+    shared.tt.resize(threads, &shared.numa_context.get_numa_config(), hash);
 
     let mut pool = ThreadPool::new(shared.clone());
     pool.set_count(threads);
@@ -1,6 +1,9 @@
 use std::sync::atomic::{AtomicPtr, AtomicU8, AtomicUsize, Ordering};
 
-use crate::types::{Move, Score, is_decisive, is_loss, is_valid, is_win};
+use crate::{
+    numa::NumaConfig,
+    types::{Move, Score, is_decisive, is_loss, is_valid, is_win},
+};
 
 pub const DEFAULT_TT_SIZE: usize = 16;
 
@@ -140,16 +143,16 @@ unsafe impl Sync for TranspositionTable {}
 
 impl TranspositionTable {
     /// Clears the transposition table. This will remove all entries but keep the allocated memory.
-    pub fn clear(&self, threads: usize) {
-        unsafe { parallel_clear(threads, self.ptr(), self.len()) };
+    pub fn clear(&self, threads: usize, numa: &NumaConfig) {
+        unsafe { parallel_clear(threads, numa, self.ptr(), self.len()) };
         self.age.store(0, Ordering::Relaxed);
     }
 
     /// Resizes the transposition table to the specified size in megabytes. This will clear all entries.
-    pub fn resize(&self, threads: usize, megabytes: usize) {
+    pub fn resize(&self, threads: usize, numa: &NumaConfig, megabytes: usize) {
         unsafe { deallocate(self.ptr(), self.len()) };
 
-        let (new_ptr, new_len) = unsafe { allocate(threads, megabytes) };
+        let (new_ptr, new_len) = unsafe { allocate(threads, numa, megabytes) };
 
         self.ptr.store(new_ptr, Ordering::Relaxed);
         self.len.store(new_len, Ordering::Relaxed);
@@ -346,7 +349,7 @@ const fn score_from_tt(score: i32, ply: isize, halfmove_clock: u8) -> i32 {
 
 impl Default for TranspositionTable {
     fn default() -> Self {
-        let (ptr, len) = unsafe { allocate(1, DEFAULT_TT_SIZE) };
+        let (ptr, len) = unsafe { allocate(1, &NumaConfig::default(), DEFAULT_TT_SIZE) };
         Self {
             ptr: AtomicPtr::new(ptr),
             len: AtomicUsize::new(len),
@@ -361,7 +364,7 @@ impl Drop for TranspositionTable {
     }
 }
 
-unsafe fn allocate(threads: usize, size_mb: usize) -> (*mut Cluster, usize) {
+unsafe fn allocate(threads: usize, numa: &NumaConfig, size_mb: usize) -> (*mut Cluster, usize) {
     #[cfg(target_os = "linux")]
     use libc::{MADV_HUGEPAGE, MAP_ANONYMOUS, MAP_PRIVATE, PROT_READ, PROT_WRITE, madvise, mmap};
 
@@ -381,7 +384,7 @@ unsafe fn allocate(threads: usize, size_mb: usize) -> (*mut Cluster, usize) {
         std::alloc::alloc_zeroed(layout).cast()
     };
 
-    unsafe { parallel_clear(threads, ptr, len) };
+    unsafe { parallel_clear(threads, numa, ptr, len) };
     (ptr, len)
 }
 
@@ -398,20 +401,34 @@ unsafe fn deallocate(ptr: *mut Cluster, len: usize) {
     }
 }
 
-unsafe fn parallel_clear<T: std::marker::Send>(threads: usize, ptr: *mut T, len: usize) {
+/// Zeroes the memory in one chunk per thread. When the search threads are bound to NUMA nodes,
+/// each chunk is zeroed from the node of the search thread with the same index, so that the
+/// first touch spreads the pages across the nodes like the threads that probe them.
+unsafe fn parallel_clear<T: std::marker::Send>(threads: usize, numa: &NumaConfig, ptr: *mut T, len: usize) {
     #[cfg(not(target_arch = "wasm32"))]
-    std::thread::scope(|scope| {
-        let slice = std::slice::from_raw_parts_mut(ptr, len);
-
-        let chunk_size = len.div_ceil(threads);
-        for chunk in slice.chunks_mut(chunk_size) {
-            scope.spawn(|| chunk.as_mut_ptr().write_bytes(0, chunk.len()));
-        }
-    });
+    {
+        let bind = numa.suggests_binding_threads(threads);
+        let nodes = numa.distribute_threads_among_numa_nodes(threads);
+
+        std::thread::scope(|scope| {
+            let slice = std::slice::from_raw_parts_mut(ptr, len);
+
+            let chunk_size = len.div_ceil(threads);
+            for (chunk, &node) in slice.chunks_mut(chunk_size).zip(&nodes) {
+                scope.spawn(move || {
+                    // A failed bind only costs locality, the chunk is still cleared
+                    if bind {
+                        let _ = numa.try_bind_current_thread_to_numa_node(node);
+                    }
+                    chunk.as_mut_ptr().write_bytes(0, chunk.len())
+                });
+            }
+        });
+    }
 
     #[cfg(target_arch = "wasm32")]
     {
-        let _ = threads;
+        let _ = (threads, numa);
         ptr.write_bytes(0, len);
     }
 }
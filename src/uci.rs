@@ -4,6 +4,7 @@ use std::sync::Arc;
 
 use crate::{
     board::{Board, NullBoardObserver},
+    numa,
     search::Report,
     thread::{SharedContext, Status, ThreadData},
     threadpool::ThreadPool,
@@ -176,7 +177,7 @@ fn compiler() {
 
 fn reset(threads: &mut ThreadPool, shared: &Arc<SharedContext>) {
     threads.clear();
-    shared.tt.clear(threads.len());
+    shared.tt.clear(threads.len(), &shared.numa_context.get_numa_config());
 
     for corrhist in shared.history.all() {
         corrhist.pawn.clear();
@@ -291,16 +292,21 @@ fn set_option(threads: &mut ThreadPool, settings: &mut Settings, shared: &Arc<Sh
             _ => eprintln!("Invalid value: '{v}'"),
         },
         ["name", "Clear", "Hash"] => {
-            shared.tt.clear(threads.len());
+            shared.tt.clear(threads.len(), &shared.numa_context.get_numa_config());
             println!("info string Hash cleared");
         }
         ["name", "Hash", "value", v] => {
-            shared.tt.resize(threads.len(), v.parse().unwrap());
+            shared.tt.resize(threads.len(), &shared.numa_context.get_numa_config(), v.parse().unwrap());
             println!("info string set Hash to {v} MB");
         }
         ["name", "Threads", "value", v] => {
             threads.set_count(v.parse().unwrap_or(1));
             println!("info string set Threads to {}", threads.len());
+
+            let cfg = shared.numa_context.get_numa_config();
+            if cfg.suggests_binding_threads(threads.len()) && numa::auto_numa_balancing_enabled() {
+                println!("info string Automatic NUMA balancing is enabled and may migrate replicated memory");
+            }
         }
         ["name", "MoveOverhead", "value", v] => {
             settings.move_overhead = v.parse().unwrap();
@@ -108,7 +108,7 @@ impl Engine {
 
     pub fn reset(&mut self) {
         self.threads.clear();
-        self.shared.tt.clear(1);
+        self.shared.tt.clear(1, &self.shared.numa_context.get_numa_config());
     }
 }
 